@@ -0,0 +1,173 @@
+//! Upload throughput of the write path, against an in-process server.
+//!
+//! Run with `cargo bench`. Each file size is uploaded over one keep-alive
+//! connection for a few seconds; the report gives MB/s and heap allocations
+//! per upload, client included. The server's own output is discarded.
+
+use argh::FromArgs;
+use petguard::{Config, build_router};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    env, fs,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+/// Counts allocations so the report can show them per upload.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        // SAFETY: forwarded unchanged to the system allocator.
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: `ptr` came from `alloc` or `realloc` above.
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        // SAFETY: forwarded unchanged to the system allocator.
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const SIZES: [usize; 4] = [4 * 1024, 256 * 1024, 4 * 1024 * 1024, 64 * 1024 * 1024];
+
+/// How long each size is measured, after one warm-up upload.
+const MEASURE: Duration = Duration::from_secs(3);
+
+const BOUNDARY: &str = "petguard-bench-boundary";
+
+#[tokio::main]
+async fn main() {
+    // `cargo bench` passes `--bench`; a name filters the sizes like `1MiB`.
+    let filter = env::args().skip(1).find(|arg| !arg.starts_with('-'));
+    let dir = env::temp_dir().join(format!("petguard-bench-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("create bench directory");
+    let save_dir = dir.to_str().expect("UTF-8 temp dir");
+    let max_request_size = (SIZES[SIZES.len() - 1] * 2).to_string();
+    let config = Config::from_args(
+        &["petguard"],
+        &[
+            "--save-dir",
+            save_dir,
+            "--max-request-size",
+            &max_request_size,
+        ],
+    )
+    .expect("bench arguments parse");
+    let app = build_router(config).await.expect("router builds");
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let addr = listener.local_addr().expect("local address");
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    silence_stdout();
+
+    for size in SIZES {
+        let label = label(size);
+        if filter
+            .as_ref()
+            .is_some_and(|filter| !label.contains(filter.as_str()))
+        {
+            continue;
+        }
+        let request = upload_request(size);
+        let mut stream = TcpStream::connect(addr).await.expect("connect");
+        upload(&mut stream, &request).await;
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let started = Instant::now();
+        let mut uploads = 0u64;
+        while started.elapsed() < MEASURE {
+            upload(&mut stream, &request).await;
+            uploads += 1;
+        }
+        let elapsed = started.elapsed().as_secs_f64();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+        eprintln!(
+            "upload/{:<6} {:>9.1} MB/s {:>9.1} uploads/s {:>10} allocations/upload",
+            label,
+            (size as u64 * uploads) as f64 / elapsed / 1e6,
+            uploads as f64 / elapsed,
+            allocations / uploads.max(1),
+        );
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+fn label(size: usize) -> String {
+    if size >= 1024 * 1024 {
+        format!("{}MiB", size / (1024 * 1024))
+    } else {
+        format!("{}KiB", size / 1024)
+    }
+}
+
+/// A complete multipart upload request of one `size`-byte file.
+fn upload_request(size: usize) -> Vec<u8> {
+    let mut body = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"bench.bin\"\r\n\
+         Content-Type: application/octet-stream\r\n\r\n",
+        BOUNDARY
+    )
+    .into_bytes();
+    body.extend((0..size).map(|i| (i % 251) as u8));
+    body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+    let mut request = format!(
+        "POST /upload HTTP/1.1\r\nHost: bench\r\n\
+         Content-Type: multipart/form-data; boundary={}\r\nContent-Length: {}\r\n\r\n",
+        BOUNDARY,
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(&body);
+    request
+}
+
+/// Sends one upload and reads its response, keeping the connection open.
+async fn upload(stream: &mut TcpStream, request: &[u8]) {
+    stream.write_all(request).await.expect("send upload");
+    let mut reader = BufReader::new(&mut *stream);
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(reader.read_u8().await.expect("read response"));
+    }
+    let head = String::from_utf8(head).expect("UTF-8 response head");
+    assert!(head.starts_with("HTTP/1.1 200"), "upload failed: {}", head);
+    let length = head
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("content-length")
+                .then(|| value.trim().parse::<usize>().ok())
+                .flatten()
+        })
+        .expect("response has a Content-Length");
+    let mut body = vec![0; length];
+    reader
+        .read_exact(&mut body)
+        .await
+        .expect("read response body");
+}
+
+/// Points stdout at /dev/null, since the server prints a line per upload;
+/// the report goes to stderr.
+fn silence_stdout() {
+    let null = fs::OpenOptions::new()
+        .write(true)
+        .open("/dev/null")
+        .expect("open /dev/null");
+    // SAFETY: both descriptors are open; fd 1 is replaced atomically.
+    unsafe { libc::dup2(std::os::fd::AsRawFd::as_raw_fd(&null), 1) };
+}
@@ -0,0 +1,101 @@
+use crate::{AppState, error::ApiError};
+use anyhow::{Result, anyhow};
+use axum::{
+    Json,
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::signal;
+
+/// Rejects requests lacking `Authorization: Bearer <admin token>`.
+pub async fn require_admin_token(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let Some(token) = presented else {
+        return Err(ApiError::Unauthorized);
+    };
+    // Every token is compared so the timing does not reveal which one matched.
+    let matched = state
+        .admin_tokens
+        .read()
+        .unwrap()
+        .iter()
+        .fold(false, |acc, expected| {
+            acc | constant_time_eq(token.as_bytes(), expected.as_bytes())
+        });
+    if matched {
+        Ok(next.run(request).await)
+    } else {
+        Err(ApiError::Unauthorized)
+    }
+}
+
+/// Collects the `--admin-token` and the tokens listed in `--admin-token-file`.
+///
+/// The file holds one token per line; blank lines and `#` comments are skipped.
+pub fn load_tokens(token: Option<&str>, file: Option<&Path>) -> Result<Vec<String>> {
+    let mut tokens: Vec<String> = token.into_iter().map(str::to_string).collect();
+    if let Some(file) = file {
+        let contents = std::fs::read_to_string(file)
+            .map_err(|e| anyhow!("Failed to read token file {:?}: {}", file, e))?;
+        tokens.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_string),
+        );
+    }
+    Ok(tokens)
+}
+
+/// Rereads the token file on every SIGHUP, keeping the old tokens if it fails.
+pub async fn reload_tokens_on_hangup(state: Arc<AppState>, token: Option<String>, file: PathBuf) {
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())
+        .expect("failed to install signal handler");
+    while hangup.recv().await.is_some() {
+        match load_tokens(token.as_deref(), Some(&file)) {
+            Ok(tokens) => {
+                println!("reloaded {} admin tokens from {:?}", tokens.len(), file);
+                *state.admin_tokens.write().unwrap() = tokens;
+            }
+            Err(e) => tracing::error!("{}", e),
+        }
+    }
+}
+
+/// Compares two byte strings without returning early on the first mismatch.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Returns the effective configuration the server was started with.
+pub async fn config(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
+    Json(state.config.clone())
+}
+
+/// Starts the same graceful shutdown as SIGTERM.
+pub async fn shutdown(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    state.shutdown.notify_one();
+    (
+        StatusCode::ACCEPTED,
+        Json(json!({"status": "shutting down"})),
+    )
+}
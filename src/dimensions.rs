@@ -0,0 +1,94 @@
+use imagesize::ImageError;
+use std::{fmt, str::FromStr};
+
+/// Most of an upload buffered while looking for its image dimensions.
+const MAX_PROBE: usize = 256 * 1024;
+
+/// Largest accepted image size, given as `WxH`.
+#[derive(Clone, Copy)]
+pub struct Dimensions {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl FromStr for Dimensions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = s
+            .split_once('x')
+            .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)));
+        match parsed {
+            Some((width, height)) => Ok(Self { width, height }),
+            None => Err(format!("invalid dimensions `{}`, expected WxH", s)),
+        }
+    }
+}
+
+/// An image upload larger than `--max-dimensions`.
+#[derive(Debug)]
+pub struct DimensionsExceeded {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl fmt::Display for DimensionsExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "image is {}x{}", self.width, self.height)
+    }
+}
+
+impl std::error::Error for DimensionsExceeded {}
+
+/// Copies the start of an upload until its image header gives the dimensions.
+///
+/// Uploads that are not images, or whose header does not fit in the first
+/// chunks read, pass unchecked.
+pub struct DimensionProbe {
+    limit: Dimensions,
+    head: Vec<u8>,
+    done: bool,
+}
+
+impl DimensionProbe {
+    pub fn new(limit: Dimensions) -> Self {
+        Self {
+            limit,
+            head: Vec::new(),
+            done: false,
+        }
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<(), DimensionsExceeded> {
+        if self.done {
+            return Ok(());
+        }
+        self.head.extend_from_slice(chunk);
+        let size = match imagesize::blob_size(&self.head) {
+            Ok(size) => size,
+            Err(ImageError::NotSupported) => {
+                self.finish();
+                return Ok(());
+            }
+            Err(_) => {
+                if self.head.len() >= MAX_PROBE {
+                    self.finish();
+                }
+                return Ok(());
+            }
+        };
+        self.finish();
+        if size.width > self.limit.width || size.height > self.limit.height {
+            return Err(DimensionsExceeded {
+                width: size.width,
+                height: size.height,
+            });
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) {
+        self.done = true;
+        self.head = Vec::new();
+    }
+}
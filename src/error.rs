@@ -0,0 +1,415 @@
+use crate::partheaders::PartHeadersExceeded;
+use axum::{
+    Json,
+    extract::multipart::MultipartError,
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use serde_json::json;
+
+/// Seconds clients refused during shutdown are asked to wait before retrying.
+const RETRY_AFTER_SECS: &str = "5";
+
+/// A request failure, answered with its HTTP status and a JSON body
+/// `{"error": <code>, "message": <text>}`.
+///
+/// The `code` strings are stable and meant for clients to branch on:
+///
+/// | code                    | status | meaning                                   |
+/// |-------------------------|--------|-------------------------------------------|
+/// | `invalid_filename`      | 400    | the filename cannot be stored safely      |
+/// | `invalid_multipart`     | 400    | the multipart body could not be parsed    |
+/// | `empty_body`            | 400    | the request has no body at all            |
+/// | `no_files`              | 400    | the request carried no file parts         |
+/// | `part_headers_exceeded` | 400    | a part has more headers than              |
+/// |                         |        | `--max-part-headers`, or more header      |
+/// |                         |        | bytes than `--max-part-header-size`       |
+/// | `too_many_fields`       | 400    | the body has more parts than allowed      |
+/// | `length_mismatch`       | 400    | a part's size differs from its declared   |
+/// |                         |        | Content-Length                            |
+/// | `missing_filename`      | 400    | a file part has no filename and           |
+/// |                         |        | `--unnamed-files` is `reject`             |
+/// | `unmapped_field`        | 400    | a file's form field has no `--field-path` |
+/// | `unexpected_message`    | 400    | a /ws-upload exchange is not a JSON       |
+/// |                         |        | header, then binary messages              |
+/// | `unauthorized`          | 401    | missing or wrong credentials              |
+/// | `invalid_token`         | 403    | the upload token is tampered or expired   |
+/// | `forbidden_name`        | 403    | the filename matches a `--deny-name` glob |
+/// |                         |        | or forbidden name of the validation file  |
+/// | `not_found`             | 404    | the named file is not stored              |
+/// | `checksum_mismatch`     | 422    | a file's digest differs from the          |
+/// |                         |        | X-Checksum-Sha256 trailer                 |
+/// | `image_too_large`       | 422    | an image exceeds the maximum dimensions   |
+/// | `malformed_content`     | 422    | a file checked by `--validate-json` or    |
+/// |                         |        | `--validate-yaml` does not parse; the     |
+/// |                         |        | message gives the parse error             |
+/// | `files_rejected`        | 422    | every file was rejected; `rejected` lists |
+/// |                         |        | why                                       |
+/// | `too_large`             | 413    | the body exceeds the size limit           |
+/// | `file_too_large`        | 413    | a file exceeds the validation `max_size`  |
+/// | `field_too_large`       | 413    | a form value exceeds its size limit       |
+/// | `extension_not_allowed` | 415    | the extension is not among the validation |
+/// |                         |        | file's `allowed_extensions`               |
+/// | `content_mismatch`      | 415    | a sniffed extension's file does not start |
+/// |                         |        | with the magic bytes of its type          |
+/// | `type_not_allowed`      | 415    | libmagic detects a type outside the       |
+/// |                         |        | `--allow-magic-type` globs                |
+/// | `expectation_failed`    | 417    | with `--strict-expect`, the Expect header |
+/// |                         |        | asks for more than `100-continue`         |
+/// | `headers_too_large`     | 431    | the request line and headers exceed       |
+/// |                         |        | `--max-header-size`                       |
+/// | `storage_unavailable`   | 500    | the file could not be written or read     |
+/// | `shutting_down`         | 503    | the server is shutting down; retry later  |
+/// | `insufficient_storage`  | 507    | the disk filled up while writing the file |
+/// |                         |        | or is below `--min-free-inodes`           |
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError {
+    InvalidFilename,
+    InvalidMultipart,
+    EmptyBody,
+    NoFiles,
+    PartHeadersExceeded,
+    TooManyFields,
+    LengthMismatch,
+    MissingFilename,
+    UnmappedField,
+    UnexpectedMessage,
+    Unauthorized,
+    InvalidToken,
+    ForbiddenName,
+    NotFound,
+    ChecksumMismatch,
+    ImageTooLarge,
+    MalformedContent(String),
+    FilesRejected(Vec<Rejection>),
+    TooLarge,
+    FileTooLarge,
+    FieldTooLarge,
+    ExtensionNotAllowed,
+    ContentMismatch,
+    TypeNotAllowed,
+    ExpectationFailed,
+    HeadersTooLarge,
+    StorageUnavailable,
+    ShuttingDown,
+    InsufficientStorage,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::InvalidFilename
+            | Self::InvalidMultipart
+            | Self::EmptyBody
+            | Self::NoFiles
+            | Self::PartHeadersExceeded
+            | Self::TooManyFields
+            | Self::LengthMismatch
+            | Self::MissingFilename
+            | Self::UnmappedField
+            | Self::UnexpectedMessage => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::InvalidToken | Self::ForbiddenName => StatusCode::FORBIDDEN,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::ChecksumMismatch
+            | Self::ImageTooLarge
+            | Self::MalformedContent(_)
+            | Self::FilesRejected(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::TooLarge | Self::FileTooLarge | Self::FieldTooLarge => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            Self::ExtensionNotAllowed | Self::ContentMismatch | Self::TypeNotAllowed => {
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            }
+            Self::ExpectationFailed => StatusCode::EXPECTATION_FAILED,
+            Self::HeadersTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Self::StorageUnavailable => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            Self::InsufficientStorage => StatusCode::INSUFFICIENT_STORAGE,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidFilename => "invalid_filename",
+            Self::InvalidMultipart => "invalid_multipart",
+            Self::EmptyBody => "empty_body",
+            Self::NoFiles => "no_files",
+            Self::PartHeadersExceeded => "part_headers_exceeded",
+            Self::TooManyFields => "too_many_fields",
+            Self::LengthMismatch => "length_mismatch",
+            Self::MissingFilename => "missing_filename",
+            Self::UnmappedField => "unmapped_field",
+            Self::UnexpectedMessage => "unexpected_message",
+            Self::Unauthorized => "unauthorized",
+            Self::InvalidToken => "invalid_token",
+            Self::ForbiddenName => "forbidden_name",
+            Self::NotFound => "not_found",
+            Self::ChecksumMismatch => "checksum_mismatch",
+            Self::ImageTooLarge => "image_too_large",
+            Self::MalformedContent(_) => "malformed_content",
+            Self::FilesRejected(_) => "files_rejected",
+            Self::TooLarge => "too_large",
+            Self::FileTooLarge => "file_too_large",
+            Self::FieldTooLarge => "field_too_large",
+            Self::ExtensionNotAllowed => "extension_not_allowed",
+            Self::ContentMismatch => "content_mismatch",
+            Self::TypeNotAllowed => "type_not_allowed",
+            Self::ExpectationFailed => "expectation_failed",
+            Self::HeadersTooLarge => "headers_too_large",
+            Self::StorageUnavailable => "storage_unavailable",
+            Self::ShuttingDown => "shutting_down",
+            Self::InsufficientStorage => "insufficient_storage",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Self::InvalidFilename => "filename is not allowed",
+            Self::InvalidMultipart => "malformed multipart body",
+            Self::EmptyBody => "request body is empty",
+            Self::NoFiles => "no files found in request",
+            Self::PartHeadersExceeded => "multipart part headers exceed the limit",
+            Self::TooManyFields => "too many multipart fields",
+            Self::LengthMismatch => "part size does not match its Content-Length",
+            Self::MissingFilename => "file part has no filename",
+            Self::UnmappedField => "form field is not mapped to a stored file",
+            Self::UnexpectedMessage => "expected a JSON header, then binary messages",
+            Self::Unauthorized => "authentication required",
+            Self::InvalidToken => "upload token is invalid or expired",
+            Self::ForbiddenName => "filename is forbidden",
+            Self::NotFound => "file not found",
+            Self::ChecksumMismatch => "file digest does not match the checksum trailer",
+            Self::ImageTooLarge => "image dimensions exceed the limit",
+            Self::MalformedContent(message) => message,
+            Self::FilesRejected(_) => "every file was rejected",
+            Self::TooLarge => "request body too large",
+            Self::FileTooLarge => "file exceeds the size limit",
+            Self::FieldTooLarge => "form field value too large",
+            Self::ExtensionNotAllowed => "file extension is not allowed",
+            Self::ContentMismatch => "file content does not match its extension",
+            Self::TypeNotAllowed => "detected file type is not allowed",
+            Self::ExpectationFailed => "only Expect: 100-continue is supported",
+            Self::HeadersTooLarge => "request headers too large",
+            Self::StorageUnavailable => "failed to store file",
+            Self::ShuttingDown => "server is shutting down, retry shortly",
+            Self::InsufficientStorage => "not enough disk space to store file",
+        }
+    }
+
+    /// Whether this turns away one file for what it is, rather than the
+    /// request as a whole or the server failing.
+    pub fn is_rejection(&self) -> bool {
+        matches!(
+            self,
+            Self::InvalidFilename
+                | Self::ForbiddenName
+                | Self::UnmappedField
+                | Self::FileTooLarge
+                | Self::ExtensionNotAllowed
+                | Self::ContentMismatch
+                | Self::TypeNotAllowed
+                | Self::ImageTooLarge
+                | Self::MalformedContent(_)
+        )
+    }
+}
+
+/// A file left out of an upload, and why.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rejection {
+    pub filename: String,
+    pub error: &'static str,
+    pub message: String,
+}
+
+impl Rejection {
+    pub fn new(filename: &str, err: &ApiError) -> Self {
+        Self {
+            filename: filename.to_string(),
+            error: err.code(),
+            message: err.message().to_string(),
+        }
+    }
+}
+
+impl From<MultipartError> for ApiError {
+    fn from(err: MultipartError) -> Self {
+        let mut source = std::error::Error::source(&err);
+        while let Some(cause) = source {
+            if cause.is::<PartHeadersExceeded>() {
+                return Self::PartHeadersExceeded;
+            }
+            source = cause.source();
+        }
+        if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            Self::TooLarge
+        } else {
+            Self::InvalidMultipart
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut body = json!({"error": self.code(), "message": self.message()});
+        if let Self::FilesRejected(rejected) = &self {
+            body["rejected"] = json!(rejected);
+        }
+        let mut response = (self.status(), Json(body)).into_response();
+        if self == Self::ShuttingDown {
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from_static(RETRY_AFTER_SECS),
+            );
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The status and code each variant answers with. There is no wildcard
+    /// arm, so a new variant does not compile until it is listed here.
+    fn expected(err: &ApiError) -> (u16, &'static str) {
+        match err {
+            ApiError::InvalidFilename => (400, "invalid_filename"),
+            ApiError::InvalidMultipart => (400, "invalid_multipart"),
+            ApiError::EmptyBody => (400, "empty_body"),
+            ApiError::NoFiles => (400, "no_files"),
+            ApiError::PartHeadersExceeded => (400, "part_headers_exceeded"),
+            ApiError::TooManyFields => (400, "too_many_fields"),
+            ApiError::LengthMismatch => (400, "length_mismatch"),
+            ApiError::MissingFilename => (400, "missing_filename"),
+            ApiError::UnmappedField => (400, "unmapped_field"),
+            ApiError::UnexpectedMessage => (400, "unexpected_message"),
+            ApiError::Unauthorized => (401, "unauthorized"),
+            ApiError::InvalidToken => (403, "invalid_token"),
+            ApiError::ForbiddenName => (403, "forbidden_name"),
+            ApiError::NotFound => (404, "not_found"),
+            ApiError::ChecksumMismatch => (422, "checksum_mismatch"),
+            ApiError::ImageTooLarge => (422, "image_too_large"),
+            ApiError::MalformedContent(_) => (422, "malformed_content"),
+            ApiError::FilesRejected(_) => (422, "files_rejected"),
+            ApiError::TooLarge => (413, "too_large"),
+            ApiError::FileTooLarge => (413, "file_too_large"),
+            ApiError::FieldTooLarge => (413, "field_too_large"),
+            ApiError::ExtensionNotAllowed => (415, "extension_not_allowed"),
+            ApiError::ContentMismatch => (415, "content_mismatch"),
+            ApiError::TypeNotAllowed => (415, "type_not_allowed"),
+            ApiError::ExpectationFailed => (417, "expectation_failed"),
+            ApiError::HeadersTooLarge => (431, "headers_too_large"),
+            ApiError::StorageUnavailable => (500, "storage_unavailable"),
+            ApiError::ShuttingDown => (503, "shutting_down"),
+            ApiError::InsufficientStorage => (507, "insufficient_storage"),
+        }
+    }
+
+    /// One error of each variant.
+    fn all() -> Vec<ApiError> {
+        vec![
+            ApiError::InvalidFilename,
+            ApiError::InvalidMultipart,
+            ApiError::EmptyBody,
+            ApiError::NoFiles,
+            ApiError::PartHeadersExceeded,
+            ApiError::TooManyFields,
+            ApiError::LengthMismatch,
+            ApiError::MissingFilename,
+            ApiError::UnmappedField,
+            ApiError::UnexpectedMessage,
+            ApiError::Unauthorized,
+            ApiError::InvalidToken,
+            ApiError::ForbiddenName,
+            ApiError::NotFound,
+            ApiError::ChecksumMismatch,
+            ApiError::ImageTooLarge,
+            ApiError::MalformedContent("bad".to_string()),
+            ApiError::FilesRejected(Vec::new()),
+            ApiError::TooLarge,
+            ApiError::FileTooLarge,
+            ApiError::FieldTooLarge,
+            ApiError::ExtensionNotAllowed,
+            ApiError::ContentMismatch,
+            ApiError::TypeNotAllowed,
+            ApiError::ExpectationFailed,
+            ApiError::HeadersTooLarge,
+            ApiError::StorageUnavailable,
+            ApiError::ShuttingDown,
+            ApiError::InsufficientStorage,
+        ]
+    }
+
+    #[tokio::test]
+    async fn every_code_has_its_status_and_body() {
+        for err in all() {
+            let (status, code) = expected(&err);
+            assert_eq!(err.code(), code);
+            assert_eq!(err.status().as_u16(), status, "{}", code);
+            assert!(!err.message().is_empty(), "{}", code);
+            let message = err.message().to_string();
+            let response = err.into_response();
+            assert_eq!(response.status().as_u16(), status, "{}", code);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["error"], code);
+            assert_eq!(body["message"], message.as_str());
+        }
+    }
+
+    #[test]
+    fn every_variant_once_and_codes_unique() {
+        let mut variants = std::collections::HashSet::new();
+        let mut codes = std::collections::HashSet::new();
+        for err in all() {
+            assert!(variants.insert(std::mem::discriminant(&err)), "{:?}", err);
+            assert!(codes.insert(expected(&err).1), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn client_and_server_faults() {
+        for err in all() {
+            let code = err.code();
+            let server_fault = matches!(
+                err,
+                ApiError::StorageUnavailable
+                    | ApiError::ShuttingDown
+                    | ApiError::InsufficientStorage
+            );
+            assert_eq!(err.status().is_server_error(), server_fault, "{}", code);
+            assert_eq!(err.status().is_client_error(), !server_fault, "{}", code);
+        }
+    }
+
+    #[tokio::test]
+    async fn shutting_down_asks_to_retry() {
+        let response = ApiError::ShuttingDown.into_response();
+        assert_eq!(response.headers()[header::RETRY_AFTER], RETRY_AFTER_SECS);
+        let response = ApiError::StorageUnavailable.into_response();
+        assert!(response.headers().get(header::RETRY_AFTER).is_none());
+    }
+
+    #[tokio::test]
+    async fn files_rejected_lists_rejections() {
+        let rejection = Rejection::new("cat.exe", &ApiError::ExtensionNotAllowed);
+        let response = ApiError::FilesRejected(vec![rejection]).into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["rejected"],
+            json!([{
+                "filename": "cat.exe",
+                "error": "extension_not_allowed",
+                "message": "file extension is not allowed",
+            }])
+        );
+    }
+}
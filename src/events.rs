@@ -0,0 +1,135 @@
+use crate::SavedFile;
+use axum::http::{HeaderMap, header};
+use serde::Serialize;
+use serde_json::{Map, Value, json};
+use std::{
+    io::ErrorKind,
+    os::unix::net::UnixDatagram,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// When an upload arrived by the server's clock, and by the client's own
+/// account in its `Date` header, both in seconds since the Unix epoch.
+///
+/// `time_skew` is the client's time minus the server's. Beyond
+/// `--max-client-time-skew` either way the upload is flagged with
+/// `time_suspicious`, since a client claiming a different time may be
+/// misconfigured or misrepresenting when it sent the file.
+///
+/// How long the upload took is measured from `arrived` on the monotonic
+/// clock, which the wall clock stepping meanwhile does not disturb.
+#[derive(Clone, Copy, Serialize)]
+pub struct UploadTimes {
+    #[serde(skip)]
+    pub arrived: Instant,
+    pub server_time: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_time: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_skew: Option<i64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub time_suspicious: bool,
+}
+
+impl UploadTimes {
+    /// Takes the server's time now, so call this as the request arrives.
+    pub fn now(headers: &HeaderMap, max_skew: u64) -> Self {
+        let server_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let client_time = headers
+            .get(header::DATE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok())
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_secs());
+        let time_skew = client_time.map(|client| client as i64 - server_time as i64);
+        let time_suspicious = time_skew.is_some_and(|skew| skew.unsigned_abs() > max_skew);
+        if let (true, Some(skew)) = (time_suspicious, time_skew) {
+            tracing::warn!("client clock is {}s off the server's", skew);
+        }
+        Self {
+            arrived: Instant::now(),
+            server_time,
+            client_time,
+            time_skew,
+            time_suspicious,
+        }
+    }
+
+    /// The times as logged with a stored file, ending with how long it has
+    /// been since the request arrived.
+    pub fn audit(&self) -> String {
+        let mut audit = format!("server_time={}", self.server_time);
+        if let (Some(client_time), Some(skew)) = (self.client_time, self.time_skew) {
+            audit.push_str(&format!(" client_time={} time_skew={}s", client_time, skew));
+            if self.time_suspicious {
+                audit.push_str(" (suspicious)");
+            }
+        }
+        audit.push_str(&format!(
+            " took={:.3}s",
+            self.arrived.elapsed().as_secs_f64()
+        ));
+        audit
+    }
+}
+
+/// Sends a JSON datagram per stored upload to `--event-socket`.
+///
+/// The socket is connected on first use and again after a failed send, so
+/// the listener may start after the server and restart at any time. Events
+/// are dropped, with a warning, while nothing is listening or its queue is full.
+pub struct EventSocket {
+    path: PathBuf,
+    socket: Mutex<Option<UnixDatagram>>,
+}
+
+impl EventSocket {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            socket: Mutex::new(None),
+        }
+    }
+
+    /// Reports a stored file with the form values sent alongside it.
+    pub fn upload(&self, file: &SavedFile, fields: &Map<String, Value>, times: &UploadTimes) {
+        let mut event = json!({"event": "upload", "file": file, "fields": fields});
+        if let (Value::Object(event), Value::Object(times)) = (&mut event, json!(times)) {
+            event.extend(times);
+        }
+        let event = event.to_string();
+        if let Err(e) = self.send(event.as_bytes()) {
+            tracing::warn!("Failed to send upload event to {:?}: {}", self.path, e);
+        }
+    }
+
+    fn send(&self, datagram: &[u8]) -> std::io::Result<()> {
+        let mut socket = self.socket.lock().unwrap();
+        let connected = match socket.take() {
+            Some(connected) => connected,
+            None => {
+                let unbound = UnixDatagram::unbound()?;
+                unbound.set_nonblocking(true)?;
+                unbound.connect(&self.path)?;
+                unbound
+            }
+        };
+        match connected.send(datagram) {
+            Ok(_) => {
+                *socket = Some(connected);
+                Ok(())
+            }
+            // The listener is alive but behind; keep the connection.
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                *socket = Some(connected);
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
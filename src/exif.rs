@@ -0,0 +1,30 @@
+use bytes::Bytes;
+use img_parts::{jpeg::Jpeg, jpeg::markers, png::Png};
+
+const JPEG_MAGIC: &[u8] = &[0xFF, 0xD8, 0xFF];
+const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// PNG chunks carrying metadata rather than pixels.
+const PNG_METADATA_CHUNKS: [[u8; 4]; 5] = [*b"eXIf", *b"tEXt", *b"iTXt", *b"zTXt", *b"tIME"];
+
+/// Whether `head`, the start of an upload, is a JPEG or PNG image.
+pub fn is_strippable(head: &[u8]) -> bool {
+    head.starts_with(JPEG_MAGIC) || head.starts_with(PNG_MAGIC)
+}
+
+/// Removes EXIF, XMP, IPTC, comments and text chunks from a JPEG or PNG image.
+pub fn strip_metadata(data: Bytes) -> Result<Bytes, img_parts::Error> {
+    if data.starts_with(JPEG_MAGIC) {
+        let mut jpeg = Jpeg::from_bytes(data)?;
+        for marker in [markers::APP1, markers::APP13, markers::COM] {
+            jpeg.remove_segments_by_marker(marker);
+        }
+        Ok(jpeg.encoder().bytes())
+    } else {
+        let mut png = Png::from_bytes(data)?;
+        for kind in PNG_METADATA_CHUNKS {
+            png.remove_chunks_by_type(kind);
+        }
+        Ok(png.encoder().bytes())
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::{Result, anyhow};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Serialize;
+use std::fmt;
+
+/// Bytes from the start of an upload that libmagic looks at, its own default.
+pub const HEAD_SIZE: usize = 1024 * 1024;
+
+/// The type libmagic identifies an upload as, with `--use-libmagic`.
+#[derive(Clone, Debug, Serialize)]
+pub struct FileType {
+    pub mime: String,
+    pub description: String,
+}
+
+/// An upload whose detected type is not among the `--allow-magic-type` globs.
+#[derive(Debug)]
+pub struct TypeNotAllowed {
+    pub mime: Option<String>,
+}
+
+impl fmt::Display for TypeNotAllowed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.mime {
+            Some(mime) => write!(f, "detected type {} is not allowed", mime),
+            None => write!(f, "file type could not be detected"),
+        }
+    }
+}
+
+impl std::error::Error for TypeNotAllowed {}
+
+/// Builds the `--allow-magic-type` globs, such as `image/*`.
+pub fn build_allowed_types(patterns: &[String]) -> Result<GlobSet> {
+    let mut set = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob =
+            Glob::new(pattern).map_err(|e| anyhow!("invalid magic type `{}`: {}", pattern, e))?;
+        set.add(glob);
+    }
+    Ok(set.build()?)
+}
+
+#[cfg(feature = "libmagic")]
+mod libmagic {
+    use super::FileType;
+    use anyhow::{Result, anyhow};
+    use magic::{
+        Cookie,
+        cookie::{DatabasePaths, Flags, Load},
+    };
+    use std::error::Error;
+
+    thread_local! {
+        // Cookies cannot move between threads, so every blocking pool thread
+        // that identifies a file loads its own.
+        static COOKIE: Result<Cookie<Load>, String> = load();
+    }
+
+    fn load() -> Result<Cookie<Load>, String> {
+        let cookie = Cookie::open(Flags::ERROR).map_err(|e| e.to_string())?;
+        cookie
+            .load(&DatabasePaths::default())
+            .map_err(|e| match e.source() {
+                Some(source) => format!("{}: {}", e, source),
+                None => e.to_string(),
+            })
+    }
+
+    pub fn check() -> Result<()> {
+        COOKIE.with(|cookie| cookie.as_ref().map(|_| ()).map_err(|e| anyhow!("{}", e)))
+    }
+
+    pub fn identify(head: &[u8]) -> Result<FileType> {
+        COOKIE.with(|cookie| {
+            let cookie = cookie.as_ref().map_err(|e| anyhow!("{}", e))?;
+            cookie.set_flags(Flags::ERROR)?;
+            let description = cookie.buffer(head)?;
+            cookie.set_flags(Flags::ERROR | Flags::MIME_TYPE)?;
+            let mime = cookie.buffer(head)?;
+            Ok(FileType { mime, description })
+        })
+    }
+}
+
+/// Checks that libmagic and its database load, so `--use-libmagic` can work.
+pub fn check_libmagic() -> Result<()> {
+    #[cfg(feature = "libmagic")]
+    return libmagic::check();
+    #[cfg(not(feature = "libmagic"))]
+    Err(anyhow!("petguard was built without the libmagic feature"))
+}
+
+/// Identifies an upload from its first [`HEAD_SIZE`] bytes.
+///
+/// Blocks while libmagic runs, and on a thread's first call while it loads
+/// the database.
+pub fn identify(head: &[u8]) -> Result<FileType> {
+    #[cfg(feature = "libmagic")]
+    return libmagic::identify(head);
+    #[cfg(not(feature = "libmagic"))]
+    {
+        let _ = head;
+        Err(anyhow!("petguard was built without the libmagic feature"))
+    }
+}
@@ -0,0 +1,53 @@
+use crate::{AppState, remove_partial, temp_path};
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use serde_json::json;
+use std::{
+    path::Path,
+    sync::{Arc, atomic::Ordering},
+};
+use tokio::fs::OpenOptions;
+
+/// Liveness: answers as long as the server can handle requests at all.
+pub async fn livez() -> impl IntoResponse {
+    Json(json!({"status": "ok"}))
+}
+
+/// Readiness: 503 while draining for shutdown or when a storage directory
+/// cannot be written to.
+pub async fn readyz(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let mut storage_ok = writable(&state.save_dir).await;
+    if let Some(mirror_dir) = &state.mirror_dir {
+        storage_ok &= writable(mirror_dir).await;
+    }
+    let status = if state.draining.load(Ordering::Relaxed) {
+        "draining"
+    } else if !storage_ok {
+        "storage unavailable"
+    } else {
+        return (StatusCode::OK, Json(json!({"status": "ready"})));
+    };
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(json!({"status": status})),
+    )
+}
+
+/// Whether a file can be created in `dir`; the probe file is removed again.
+async fn writable(dir: &Path) -> bool {
+    let probe = temp_path(&dir.join("readyz"));
+    let created = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .await;
+    match created {
+        Ok(_) => {
+            remove_partial(&probe).await;
+            true
+        }
+        Err(e) => {
+            tracing::warn!("{:?} is not writable: {}", dir, e);
+            false
+        }
+    }
+}
@@ -0,0 +1,3707 @@
+//! An HTTP upload server, run by the `petguard` binary and embeddable through
+//! [`run`] or [`build_router`].
+//!
+//! A [`Config`] takes the binary's flags; build one with
+//! [`FromArgs::from_args`], as in
+//! `Config::from_args(&["petguard"], &["--save-dir", "uploads"])`, and
+//! adjust its fields afterwards if need be.
+
+// `effective_config` lists every flag in one `json!`, which outgrows the default.
+#![recursion_limit = "256"]
+
+#[cfg(not(unix))]
+compile_error!("This program requires a Unix-based OS.");
+
+mod admin;
+mod dimensions;
+mod error;
+mod events;
+mod exif;
+mod filetype;
+mod health;
+mod listener;
+mod logs;
+mod metrics;
+mod naming;
+mod objectstore;
+mod openapi;
+mod partheaders;
+mod phash;
+mod pidfile;
+mod repeats;
+mod s3;
+mod signed;
+mod syntax;
+mod throttle;
+mod thumbnail;
+mod trailer;
+mod upgrade;
+mod validation;
+mod ws;
+mod xattr;
+
+pub use dimensions::Dimensions;
+pub use naming::NameTemplate;
+
+use anyhow::{Result, anyhow};
+use argh::FromArgs;
+use async_compression::tokio::{
+    bufread::{GzipDecoder, ZstdDecoder},
+    write::{GzipEncoder, ZstdEncoder},
+};
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{
+        DefaultBodyLimit, Extension, FromRequest, Multipart, Request, State, multipart::Field,
+    },
+    http::{HeaderMap, HeaderValue, StatusCode, Uri, header},
+    middleware,
+    response::{Html, IntoResponse, Response},
+    routing::{MethodRouter, any, get, post},
+    serve::Listener,
+};
+use bytes::Bytes;
+use dimensions::{DimensionProbe, DimensionsExceeded};
+use error::{ApiError, Rejection};
+use events::{EventSocket, UploadTimes};
+use filetype::{FileType, TypeNotAllowed};
+use globset::GlobSet;
+use http_body::Body as _;
+use listener::{LimitedListener, track_in_flight};
+use logs::LogStream;
+use metrics::Metrics;
+use naming::NameMeta;
+use nix::{
+    errno::Errno,
+    sys::statvfs::statvfs,
+    unistd::{
+        Gid, Group, Uid, User, chown, getegid, geteuid, getgid, getuid, setgid, setgroups, setuid,
+    },
+};
+use objectstore::ObjectStore;
+use partheaders::{LimitedParts, PartLimits, parse_boundary};
+use pidfile::PidFile;
+use repeats::RepeatLog;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use signed::UploadGrant;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::Permissions,
+    io::ErrorKind,
+    net::SocketAddr,
+    os::{
+        fd::AsFd,
+        unix::fs::{MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use syntax::{Malformed, Syntax};
+use tempfile::TempDir;
+use throttle::RateLimiter;
+use tokio::{
+    fs,
+    fs::{File, OpenOptions, set_permissions},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    process::Command,
+    signal,
+    sync::{Notify, Semaphore, mpsc},
+    task::{self, JoinSet},
+};
+use tower_http::{
+    compression::CompressionLayer,
+    normalize_path::NormalizePath,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    timeout::TimeoutLayer,
+    trace::TraceLayer,
+};
+use tracing::Instrument;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use trailer::Trailers;
+use validation::{
+    FieldMeta, MagicProbe, PolicySource, RejectReason, SizeExceeded, ValidationPolicy,
+};
+
+#[derive(FromArgs)]
+/// Reach new heights.
+pub struct Config {
+    /// receive port number (defaults to $PORT, then 8080)
+    #[argh(option, short = 'p')]
+    pub port: Option<u16>,
+
+    /// save directory, optional with --s3-bucket
+    #[argh(option, short = 's')]
+    pub save_dir: Option<PathBuf>,
+
+    /// mount point that must be mounted before the save directory is used
+    #[argh(option)]
+    pub wait_for_mount: Option<PathBuf>,
+
+    /// seconds to wait for --wait-for-mount before giving up
+    #[argh(option, default = "60")]
+    pub mount_timeout: u64,
+
+    /// second directory every upload is also stored in
+    #[argh(option)]
+    pub mirror_dir: Option<PathBuf>,
+
+    /// bucket of an S3-compatible object store every upload is also put in,
+    /// with credentials from $AWS_ACCESS_KEY_ID and $AWS_SECRET_ACCESS_KEY;
+    /// without --save-dir files are only kept there
+    #[argh(option)]
+    pub s3_bucket: Option<String>,
+
+    /// URL of the S3-compatible service --s3-bucket is on (defaults to Amazon
+    /// S3 in --s3-region)
+    #[argh(option)]
+    pub s3_endpoint: Option<String>,
+
+    /// region of --s3-bucket
+    #[argh(option, default = "String::from(\"us-east-1\")")]
+    pub s3_region: String,
+
+    /// store files with the given extensions in a subdirectory, as
+    /// jpg,png=images (repeatable)
+    #[argh(option)]
+    pub route_ext: Vec<String>,
+
+    /// path files are stored under, relative to the save directory, built
+    /// from {date}, {uuid}, {orig}, {stem}, {ext} and {sha256}
+    #[argh(option)]
+    pub name_template: Option<NameTemplate>,
+
+    /// store the file of a form field under a fixed name instead of its
+    /// filename, as field=name (repeatable)
+    #[argh(option)]
+    pub field_path: Vec<String>,
+
+    /// reject files from form fields without a --field-path
+    #[argh(switch)]
+    pub reject_unmapped_fields: bool,
+
+    /// what to do with parts that have a Content-Type but no filename: field
+    /// (keep as a form value), reject, or generate a name
+    #[argh(option, default = "UnnamedFiles::Field")]
+    pub unnamed_files: UnnamedFiles,
+
+    /// save file owner (user or user:group)
+    #[argh(option)]
+    pub owner: Option<String>,
+
+    /// keep the file with a warning when chown is not permitted
+    #[argh(switch)]
+    pub owner_best_effort: bool,
+
+    /// user:group to switch to after binding the port
+    #[argh(option)]
+    pub run_as: Option<String>,
+
+    /// file to write the process ID to, removed again on shutdown
+    #[argh(option)]
+    pub pid_file: Option<PathBuf>,
+
+    /// warn and overwrite instead of refusing to start when --pid-file names
+    /// a running process
+    #[argh(switch)]
+    pub pid_file_takeover: bool,
+
+    /// maximum number of open connections
+    #[argh(option)]
+    pub max_connections: Option<usize>,
+
+    /// maximum number of open connections from one IP address
+    #[argh(option)]
+    pub max_conn_per_ip: Option<usize>,
+
+    /// seconds a connection may send and receive nothing before it is closed,
+    /// WebSockets included
+    #[argh(option)]
+    pub idle_timeout: Option<u64>,
+
+    /// file permission
+    #[argh(option)]
+    pub mode: Option<String>,
+
+    /// save directory permission
+    #[argh(option)]
+    pub dir_mode: Option<String>,
+
+    /// nest stored files this many directories deep, named after the
+    /// leading hex digits of their name's SHA-256, as ab/cd/file at 2
+    #[argh(option, default = "0")]
+    pub shard_depth: usize,
+
+    /// seconds before any request is answered with 504
+    #[argh(option)]
+    pub response_timeout: Option<u64>,
+
+    /// seconds before requests to one route are answered with 504, as
+    /// /upload=600, overriding --response-timeout (repeatable)
+    #[argh(option)]
+    pub route_timeout: Vec<String>,
+
+    /// maximum upload rate per connection in bytes/sec
+    #[argh(option)]
+    pub max_rate: Option<u64>,
+
+    /// maximum upload rate across all connections in bytes/sec
+    #[argh(option)]
+    pub max_rate_total: Option<u64>,
+
+    /// maximum number of multipart fields per request, files or not
+    #[argh(option, default = "1000")]
+    pub max_fields: usize,
+
+    /// bytes of a form value held in memory; a longer one is stored as a file
+    /// with --unnamed-files generate and refused with 413 otherwise
+    #[argh(option, default = "64 * 1024")]
+    pub max_form_value: usize,
+
+    /// maximum size in bytes of the request line and headers, answered with 431
+    #[argh(option, default = "16 * 1024")]
+    pub max_header_size: usize,
+
+    /// answer requests whose Expect header is anything but 100-continue with 417
+    #[argh(switch)]
+    pub strict_expect: bool,
+
+    /// maximum number of headers of one multipart part, at most 32
+    #[argh(option, default = "partheaders::MAX_PART_HEADERS")]
+    pub max_part_headers: usize,
+
+    /// maximum size in bytes of one multipart part's headers
+    #[argh(option, default = "8 * 1024")]
+    pub max_part_header_size: usize,
+
+    /// maximum size in bytes of a whole upload body, all fields together, and
+    /// of a file sent over /ws-upload
+    #[argh(option, default = "2 * 1024 * 1024")]
+    pub max_request_size: usize,
+
+    /// refuse uploads with 507 while fewer inodes than this are free
+    #[argh(option)]
+    pub min_free_inodes: Option<u64>,
+
+    /// reject parts whose size differs from their declared Content-Length
+    #[argh(switch)]
+    pub verify_length: bool,
+
+    /// extended attribute set on every stored file, as namespace.key=value
+    /// (repeatable)
+    #[argh(option)]
+    pub xattr: Vec<String>,
+
+    /// record the filename, upload time and --use-libmagic type in
+    /// user.petguard.* extended attributes
+    #[argh(switch)]
+    pub xattr_metadata: bool,
+
+    /// remove EXIF and other metadata from JPEG and PNG uploads
+    #[argh(switch)]
+    pub strip_exif: bool,
+
+    /// report a perceptual hash of image uploads
+    #[argh(switch)]
+    pub phash: bool,
+
+    /// reject images wider or taller than WxH pixels
+    #[argh(option)]
+    pub max_dimensions: Option<Dimensions>,
+
+    /// store a JPEG thumbnail of image uploads fitting within WxH pixels
+    /// beside them, as <name>.thumb.jpg
+    #[argh(option)]
+    pub thumbnail: Option<Dimensions>,
+
+    /// replace stored files whose names differ from an upload's only by case
+    #[argh(switch)]
+    pub case_insensitive_conflict: bool,
+
+    /// reject filenames starting with a dot
+    #[argh(switch)]
+    pub reject_dotfiles: bool,
+
+    /// reject filenames matching this glob with 403 (repeatable)
+    #[argh(option)]
+    pub deny_name: Vec<String>,
+
+    /// match --deny-name globs case-insensitively
+    #[argh(switch)]
+    pub deny_name_ignore_case: bool,
+
+    /// extensions, such as exe,sh,php, whose files must start with the magic
+    /// bytes of their type (repeatable)
+    #[argh(option)]
+    pub sniff_ext: Vec<String>,
+
+    /// reject .json files that do not parse, with 422
+    #[argh(switch)]
+    pub validate_json: bool,
+
+    /// reject .yaml and .yml files that do not parse, with 422
+    #[argh(switch)]
+    pub validate_yaml: bool,
+
+    /// identify uploads with libmagic and report the type as file_type
+    #[argh(switch)]
+    pub use_libmagic: bool,
+
+    /// MIME types, such as image/* or application/pdf, one of which libmagic
+    /// must detect for a file to be stored (repeatable; needs --use-libmagic)
+    #[argh(option)]
+    pub allow_magic_type: Vec<String>,
+
+    /// TOML file whose [validation] section sets upload rules, reloaded on SIGHUP
+    #[argh(option)]
+    pub validation_file: Option<PathBuf>,
+
+    /// store the acceptable files of an upload and list the rejected ones
+    /// under rejected, instead of failing it for one rejected file
+    #[argh(switch)]
+    pub collect_rejections: bool,
+
+    /// when a multipart body turns out truncated or malformed partway, keep
+    /// the files stored before that and report the error as incomplete
+    #[argh(switch)]
+    pub partial_ok: bool,
+
+    /// move files of failed uploads into .failed in the save directory instead of deleting them
+    #[argh(switch)]
+    pub keep_partial: bool,
+
+    /// number of file parts of one request written concurrently
+    #[argh(option, default = "1")]
+    pub parallel_fields: usize,
+
+    /// maximum number of files whose mode and owner are set concurrently
+    #[argh(option, default = "16")]
+    pub max_ownership_ops: usize,
+
+    /// times a final rename failing with ESTALE or EBUSY, as on NFS or CIFS,
+    /// is retried with backoff
+    #[argh(option, default = "3")]
+    pub rename_retries: u32,
+
+    /// compress stored files with gzip or zstd
+    #[argh(option)]
+    pub compress_at_rest: Option<Compression>,
+
+    /// send responses uncompressed even to clients accepting gzip or br, for
+    /// debugging
+    #[argh(switch)]
+    pub no_response_compression: bool,
+
+    /// hard-link uploads whose content is already stored
+    #[argh(switch)]
+    pub dedup: bool,
+
+    /// rename a key of the upload response, as old=new (repeatable)
+    #[argh(option)]
+    pub response_key: Vec<String>,
+
+    /// log an upload error repeated within this many seconds only once, then
+    /// how often it recurred every interval
+    #[argh(option)]
+    pub log_repeat_interval: Option<u64>,
+
+    /// serve request counts and latencies in the Prometheus format at /metrics
+    #[argh(switch)]
+    pub metrics: bool,
+
+    /// serve the /admin endpoints
+    #[argh(switch)]
+    pub enable_admin: bool,
+
+    /// bearer token required by the /admin endpoints
+    #[argh(option)]
+    pub admin_token: Option<String>,
+
+    /// HMAC secret signing upload grants; uploads then need a valid grant
+    #[argh(option)]
+    pub upload_secret: Option<String>,
+
+    /// file of bearer tokens for the /admin endpoints, one per line, reloaded on SIGHUP
+    #[argh(option)]
+    pub admin_token_file: Option<PathBuf>,
+
+    /// command run with the saved path after each upload
+    #[argh(option)]
+    pub on_upload_cmd: Option<PathBuf>,
+
+    /// unix datagram socket sent a JSON event after each upload
+    #[argh(option)]
+    pub event_socket: Option<PathBuf>,
+
+    /// seconds the Date header of an upload may differ from the server's
+    /// clock before its event is flagged with time_suspicious
+    #[argh(option, default = "300")]
+    pub max_client_time_skew: u64,
+
+    /// icon (.ico, .png or .svg) served as /favicon.ico instead of an empty 204
+    #[argh(option)]
+    pub favicon: Option<PathBuf>,
+
+    /// milliseconds to hold every upload response, for testing client timeouts;
+    /// requires PETGUARD_ALLOW_DEBUG=1
+    #[argh(option, hidden_help)]
+    pub debug_delay: Option<u64>,
+}
+
+/// Runs the upload server until it is shut down, as the `petguard` binary does.
+///
+/// Installs the tracing subscriber unless one is already set, binds the port
+/// or adopts the socket of the process being upgraded, and drops privileges
+/// with `--run-as`.
+pub async fn run(args: Config) -> Result<()> {
+    let logs = args.enable_admin.then(LogStream::new);
+    // Another subscriber may already be installed when embedded; serving
+    // without our logs beats not serving at all.
+    let installed = tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| format!("{}=debug", env!("CARGO_CRATE_NAME")).into()),
+        )
+        .with(tracing_subscriber::fmt::layer())
+        .with(logs.clone())
+        .try_init();
+    if let Err(e) = installed {
+        eprintln!("warning: continuing without logging: {}", e);
+    }
+
+    let run_as = args.run_as.as_deref().map(parse_owner).transpose()?;
+    // Held until `run` returns, which removes the file again.
+    let _pid_file = args
+        .pid_file
+        .as_deref()
+        .map(|path| PidFile::create(path, args.pid_file_takeover))
+        .transpose()?;
+    let (app, state) = build(&args, logs).await?;
+    let addr = SocketAddr::from(([0, 0, 0, 0], listen_port(&args)?));
+    let listener = match upgrade::inherited_listener()? {
+        Some(inherited) => tokio::net::TcpListener::from_std(inherited)?,
+        None => tokio::net::TcpListener::bind(addr).await?,
+    };
+    tokio::spawn(upgrade::upgrade_on_signal(
+        listener.as_fd().try_clone_to_owned()?,
+        state.clone(),
+    ));
+    let listener = LimitedListener::new(
+        listener,
+        args.max_connections,
+        args.max_conn_per_ip,
+        args.idle_timeout.map(Duration::from_secs),
+    );
+    if let Some(run_as) = &run_as {
+        drop_privileges(run_as)?;
+    }
+    println!("listening on {}", listener.local_addr()?);
+    let open_connections = listener.open_connections();
+    // Normalizing has to wrap the router so it runs before routes are matched.
+    let app = NormalizePath::trim_trailing_slash(app.layer(middleware::from_fn(track_in_flight)));
+    listener::serve(listener, app, args.max_header_size, async move {
+        shutdown_signal(state).await;
+        println!(
+            "stopped accepting, draining {} connections",
+            open_connections.load(Ordering::Relaxed)
+        );
+    })
+    .await;
+    println!("all connections drained");
+    Ok(())
+}
+
+/// Builds the router serving uploads for `args`, to embed the server next to
+/// routes of one's own.
+///
+/// Like [`run`], this waits for `--wait-for-mount`, prepares the save
+/// directory and starts the SIGHUP reload tasks; binding, privileges and
+/// shutdown are left to the caller. That waiting is why it is async, and
+/// flags that do not fit together, a missing save directory or an unknown
+/// `--owner` fail it rather than the first upload.
+pub async fn build_router(args: Config) -> Result<Router> {
+    Ok(build(&args, None).await?.0)
+}
+
+/// `--port`, falling back to `$PORT` and then 8080.
+fn listen_port(args: &Config) -> Result<u16> {
+    match args.port {
+        Some(port) => Ok(port),
+        None => match std::env::var("PORT") {
+            Ok(port) => port
+                .parse()
+                .map_err(|e| anyhow!("invalid PORT `{}`: {}", port, e)),
+            Err(_) => Ok(8080),
+        },
+    }
+}
+
+async fn build(args: &Config, logs: Option<LogStream>) -> Result<(Router, Arc<AppState>)> {
+    let mode = args.mode.as_deref().map(parse_mode).transpose()?;
+    let dir_mode = args.dir_mode.as_deref().map(parse_mode).transpose()?;
+    let owner = args.owner.as_deref().map(parse_owner).transpose()?;
+
+    let ext_dirs = parse_ext_routes(&args.route_ext)?;
+    let field_paths = parse_field_paths(&args.field_path, args.reject_dotfiles)?;
+    if args.reject_unmapped_fields && field_paths.is_empty() {
+        return Err(anyhow!("--reject-unmapped-fields requires --field-path"));
+    }
+    let xattrs = args
+        .xattr
+        .iter()
+        .map(|spec| xattr::parse_xattr(spec))
+        .collect::<Result<Vec<_>>>()?;
+    let favicon = args.favicon.as_deref().map(load_favicon).transpose()?;
+    let policy_source = PolicySource {
+        forbidden_names: args.deny_name.clone(),
+        ignore_case: args.deny_name_ignore_case,
+        max_dimensions: args.max_dimensions,
+        sniff_extensions: args
+            .sniff_ext
+            .iter()
+            .flat_map(|list| list.split(','))
+            .map(str::to_string)
+            .collect(),
+        file: args.validation_file.clone(),
+    };
+    let validation = policy_source.load()?;
+    let route_timeouts = parse_route_timeouts(&args.route_timeout)?;
+    let response_keys = args
+        .response_key
+        .iter()
+        .map(|spec| parse_key_rename(spec))
+        .collect::<Result<HashMap<_, _>>>()?;
+    if args.enable_admin && args.admin_token.is_none() && args.admin_token_file.is_none() {
+        return Err(anyhow!(
+            "--enable-admin requires --admin-token or --admin-token-file"
+        ));
+    }
+    let debug_delay = args.debug_delay.map(Duration::from_millis);
+    if let Some(delay) = debug_delay {
+        if std::env::var("PETGUARD_ALLOW_DEBUG").as_deref() != Ok("1") {
+            return Err(anyhow!("--debug-delay requires PETGUARD_ALLOW_DEBUG=1"));
+        }
+        tracing::warn!(
+            "--debug-delay is set, every upload response is held for {:?}",
+            delay
+        );
+    }
+    if args.max_conn_per_ip == Some(0) {
+        return Err(anyhow!("--max-conn-per-ip must be at least 1"));
+    }
+    if !args.allow_magic_type.is_empty() && !args.use_libmagic {
+        return Err(anyhow!("--allow-magic-type requires --use-libmagic"));
+    }
+    let allowed_magic_types = match args.allow_magic_type.as_slice() {
+        [] => None,
+        types => Some(filetype::build_allowed_types(types)?),
+    };
+    // Without the database files are still stored, unless their type has to
+    // be enforced.
+    let libmagic = match args.use_libmagic.then(filetype::check_libmagic) {
+        Some(Ok(())) => true,
+        Some(Err(e)) if allowed_magic_types.is_some() => {
+            return Err(anyhow!("--allow-magic-type needs libmagic: {:#}", e));
+        }
+        Some(Err(e)) => {
+            tracing::warn!(
+                "libmagic is unavailable, storing files without a type: {:#}",
+                e
+            );
+            false
+        }
+        None => false,
+    };
+    if args.idle_timeout == Some(0) {
+        return Err(anyhow!("--idle-timeout must be at least 1"));
+    }
+    if args
+        .thumbnail
+        .is_some_and(|size| size.width == 0 || size.height == 0)
+    {
+        return Err(anyhow!("--thumbnail must be at least 1x1"));
+    }
+    if args.shard_depth > 64 / naming::SHARD_WIDTH {
+        return Err(anyhow!(
+            "--shard-depth must be at most {}",
+            64 / naming::SHARD_WIDTH
+        ));
+    }
+    if args.log_repeat_interval == Some(0) {
+        return Err(anyhow!("--log-repeat-interval must be at least 1"));
+    }
+    if !(1..=partheaders::MAX_PART_HEADERS).contains(&args.max_part_headers) {
+        return Err(anyhow!(
+            "--max-part-headers must be between 1 and {}",
+            partheaders::MAX_PART_HEADERS
+        ));
+    }
+    if args.max_form_value == 0 {
+        return Err(anyhow!("--max-form-value must be at least 1"));
+    }
+    if args.max_part_header_size == 0 {
+        return Err(anyhow!("--max-part-header-size must be at least 1"));
+    }
+    if args.parallel_fields == 0 {
+        return Err(anyhow!("--parallel-fields must be at least 1"));
+    }
+    if args.max_ownership_ops == 0 {
+        return Err(anyhow!("--max-ownership-ops must be at least 1"));
+    }
+    if args.s3_bucket.is_none() {
+        if args.save_dir.is_none() {
+            return Err(anyhow!("--save-dir is required unless --s3-bucket is set"));
+        }
+        if args.s3_endpoint.is_some() {
+            return Err(anyhow!("--s3-endpoint requires --s3-bucket"));
+        }
+    }
+    // These work on the stored files, which only --save-dir keeps.
+    let local_only = [
+        (args.wait_for_mount.is_some(), "--wait-for-mount"),
+        (args.mirror_dir.is_some(), "--mirror-dir"),
+        (args.dedup, "--dedup"),
+        (args.thumbnail.is_some(), "--thumbnail"),
+        (args.keep_partial, "--keep-partial"),
+        (args.on_upload_cmd.is_some(), "--on-upload-cmd"),
+    ];
+    if args.save_dir.is_none()
+        && let Some((_, flag)) = local_only.iter().find(|(set, _)| *set)
+    {
+        return Err(anyhow!("{} requires --save-dir", flag));
+    }
+    let objects = match &args.s3_bucket {
+        Some(bucket) => Some(Box::new(s3::S3Bucket::new(
+            args.s3_endpoint.as_deref(),
+            bucket,
+            &args.s3_region,
+            s3::Credentials::from_env()?,
+        )?) as Box<dyn ObjectStore>),
+        None => None,
+    };
+    // Without a save directory, files wait for the bucket in a private one.
+    let (save_dir, spool) = match &args.save_dir {
+        Some(dir) => (dir.clone(), None),
+        None => {
+            let spool = tempfile::Builder::new().prefix("petguard-").tempdir()?;
+            (spool.path().to_path_buf(), Some(spool))
+        }
+    };
+    let admin_tokens = admin::load_tokens(
+        args.admin_token.as_deref(),
+        args.admin_token_file.as_deref(),
+    )?;
+
+    let config = effective_config(args, listen_port(args)?);
+
+    if let Some(mount_point) = &args.wait_for_mount {
+        wait_for_mount(mount_point, Duration::from_secs(args.mount_timeout)).await?;
+    }
+    let dedup_index = if args.dedup {
+        let subdirs = ext_dirs.values().cloned().collect::<Vec<_>>();
+        Some(Mutex::new(
+            build_dedup_index(&save_dir, &subdirs, args.shard_depth, args.compress_at_rest).await?,
+        ))
+    } else {
+        None
+    };
+
+    let state = Arc::new(AppState {
+        save_dir,
+        spool,
+        mirror_dir: args.mirror_dir.clone(),
+        objects,
+        ext_dirs,
+        name_template: args.name_template.clone(),
+        field_paths,
+        reject_unmapped_fields: args.reject_unmapped_fields,
+        unnamed_files: args.unnamed_files,
+        mode,
+        dir_mode,
+        shard_depth: args.shard_depth,
+        owner,
+        owner_best_effort: args.owner_best_effort,
+        on_upload_cmd: args.on_upload_cmd.clone(),
+        events: args.event_socket.clone().map(EventSocket::new),
+        max_client_time_skew: args.max_client_time_skew,
+        favicon,
+        logs,
+        errors: Arc::new(RepeatLog::new(
+            args.log_repeat_interval.map(Duration::from_secs),
+        )),
+        metrics: args.metrics.then(Metrics::default),
+        compression: args.compress_at_rest,
+        max_fields: args.max_fields,
+        max_form_value: args.max_form_value,
+        max_request_size: args.max_request_size,
+        part_limits: PartLimits {
+            max_headers: args.max_part_headers,
+            max_bytes: args.max_part_header_size,
+        },
+        verify_length: args.verify_length,
+        xattrs,
+        xattr_metadata: args.xattr_metadata,
+        xattr_unsupported: AtomicBool::new(false),
+        strip_exif: args.strip_exif,
+        phash: args.phash,
+        thumbnail: args.thumbnail,
+        validate_json: args.validate_json,
+        validate_yaml: args.validate_yaml,
+        libmagic,
+        allowed_magic_types,
+        case_insensitive_conflict: args.case_insensitive_conflict,
+        reject_dotfiles: args.reject_dotfiles,
+        validation: RwLock::new(Arc::new(validation)),
+        upload_secret: args.upload_secret.clone().map(String::into_bytes),
+        collect_rejections: args.collect_rejections,
+        partial_ok: args.partial_ok,
+        keep_partial: args.keep_partial,
+        parallel_fields: args.parallel_fields,
+        ownership_ops: Semaphore::new(args.max_ownership_ops),
+        rename_retries: args.rename_retries,
+        min_free_inodes: args.min_free_inodes,
+        max_rate: args.max_rate,
+        total_rate: args
+            .max_rate_total
+            .map(|rate| Mutex::new(RateLimiter::new(rate))),
+        dedup_index,
+        response_keys,
+        admin_tokens: RwLock::new(admin_tokens),
+        config,
+        shutdown: Notify::new(),
+        debug_delay,
+        draining: AtomicBool::new(false),
+    });
+    prepare_save_dir(&state).await?;
+    tokio::spawn(repeats::summarize(state.errors.clone()));
+    if policy_source.file.is_some() {
+        tokio::spawn(validation::reload_policy_on_hangup(
+            state.clone(),
+            policy_source,
+        ));
+    }
+    if let Some(path) = args.admin_token_file.clone() {
+        tokio::spawn(admin::reload_tokens_on_hangup(
+            state.clone(),
+            args.admin_token.clone(),
+            path,
+        ));
+    }
+    // Each route gets its own timeout layer, so a generous --route-timeout
+    // for uploads leaves the health probes on the short default.
+    let mut timed_routes = HashSet::new();
+    let mut timed = |path: &'static str, route| {
+        timed_routes.insert(path);
+        let secs = route_timeouts.get(path).copied().or(args.response_timeout);
+        with_timeout(route, secs)
+    };
+    let mut app = Router::new()
+        .route("/", timed("/", get(test_handler)))
+        .route("/favicon.ico", timed("/favicon.ico", get(serve_favicon)))
+        .route(
+            "/upload",
+            timed(
+                "/upload",
+                post(upload)
+                    .layer(DefaultBodyLimit::max(args.max_request_size))
+                    .layer(middleware::from_fn(trailer::capture_trailers)),
+            ),
+        )
+        .route("/verify", timed("/verify", post(verify)))
+        .route(
+            "/openapi.json",
+            timed("/openapi.json", get(openapi::openapi)),
+        )
+        .route("/livez", timed("/livez", get(health::livez)))
+        .route("/readyz", timed("/readyz", get(health::readyz)))
+        .route("/ws-upload", timed("/ws-upload", get(ws::ws_upload)));
+    if args.enable_admin {
+        let mut admin = Router::new()
+            .route("/config", timed("/admin/config", get(admin::config)))
+            .route("/shutdown", timed("/admin/shutdown", post(admin::shutdown)));
+        // Only `run` installs the layer feeding it.
+        if state.logs.is_some() {
+            admin = admin.route("/logs", timed("/admin/logs", get(logs::logs)));
+        }
+        if args.upload_secret.is_some() {
+            admin = admin.route(
+                "/upload-token",
+                timed("/admin/upload-token", post(signed::upload_token)),
+            );
+        }
+        let admin = admin.route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            admin::require_admin_token,
+        ));
+        app = app.nest("/admin", admin);
+    }
+    if state.metrics.is_some() {
+        app = app.route("/metrics", timed("/metrics", get(metrics::serve)));
+    }
+    if let Some(path) = route_timeouts
+        .keys()
+        .find(|path| !timed_routes.contains(path.as_str()))
+    {
+        return Err(anyhow!("--route-timeout names unknown route {}", path));
+    }
+    let mut app = app
+        .fallback(with_timeout(any(handler_404), args.response_timeout))
+        .with_state(state.clone());
+    if state.metrics.is_some() {
+        // Layered on the router so the matched route is known, and outside
+        // the timeout so the requests it cuts off are counted too.
+        app = app.layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track,
+        ));
+    }
+    if !args.no_response_compression {
+        // The default predicate leaves images, event streams and tiny
+        // bodies alone.
+        app = app.layer(CompressionLayer::new());
+    }
+    if args.strict_expect {
+        app = app.layer(middleware::from_fn(reject_unknown_expect));
+    }
+    // Every response carries the request's X-Request-Id, taken from the client
+    // or generated, and everything logged while handling it is tagged with it.
+    let app = app
+        .layer(middleware::from_fn_with_state(
+            args.max_header_size,
+            limit_header_size,
+        ))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+    Ok((app, state))
+}
+
+/// The settings in effect after defaults and `$PORT` are applied, with the
+/// admin token redacted.
+fn effective_config(args: &Config, port: u16) -> serde_json::Value {
+    json!({
+        "port": port,
+        "save_dir": args.save_dir,
+        "mirror_dir": args.mirror_dir,
+        "s3_bucket": args.s3_bucket,
+        "s3_endpoint": args.s3_endpoint,
+        "s3_region": args.s3_region,
+        "route_ext": args.route_ext,
+        "name_template": args.name_template.as_ref().map(ToString::to_string),
+        "field_path": args.field_path,
+        "reject_unmapped_fields": args.reject_unmapped_fields,
+        "unnamed_files": args.unnamed_files.name(),
+        "wait_for_mount": args.wait_for_mount,
+        "mount_timeout": args.mount_timeout,
+        "owner": args.owner,
+        "owner_best_effort": args.owner_best_effort,
+        "run_as": args.run_as,
+        "pid_file": args.pid_file,
+        "pid_file_takeover": args.pid_file_takeover,
+        "max_connections": args.max_connections,
+        "max_conn_per_ip": args.max_conn_per_ip,
+        "idle_timeout": args.idle_timeout,
+        "mode": args.mode,
+        "dir_mode": args.dir_mode,
+        "shard_depth": args.shard_depth,
+        "response_timeout": args.response_timeout,
+        "route_timeout": args.route_timeout,
+        "max_rate": args.max_rate,
+        "max_rate_total": args.max_rate_total,
+        "max_fields": args.max_fields,
+        "max_form_value": args.max_form_value,
+        "max_header_size": args.max_header_size,
+        "strict_expect": args.strict_expect,
+        "max_part_headers": args.max_part_headers,
+        "max_part_header_size": args.max_part_header_size,
+        "max_request_size": args.max_request_size,
+        "min_free_inodes": args.min_free_inodes,
+        "verify_length": args.verify_length,
+        "xattr": args.xattr,
+        "xattr_metadata": args.xattr_metadata,
+        "strip_exif": args.strip_exif,
+        "phash": args.phash,
+        "max_dimensions": args
+            .max_dimensions
+            .map(|d| format!("{}x{}", d.width, d.height)),
+        "thumbnail": args
+            .thumbnail
+            .map(|d| format!("{}x{}", d.width, d.height)),
+        "case_insensitive_conflict": args.case_insensitive_conflict,
+        "reject_dotfiles": args.reject_dotfiles,
+        "deny_name": args.deny_name,
+        "deny_name_ignore_case": args.deny_name_ignore_case,
+        "sniff_ext": args.sniff_ext,
+        "validate_json": args.validate_json,
+        "validate_yaml": args.validate_yaml,
+        "use_libmagic": args.use_libmagic,
+        "allow_magic_type": args.allow_magic_type,
+        "validation_file": args.validation_file,
+        "collect_rejections": args.collect_rejections,
+        "partial_ok": args.partial_ok,
+        "keep_partial": args.keep_partial,
+        "parallel_fields": args.parallel_fields,
+        "max_ownership_ops": args.max_ownership_ops,
+        "rename_retries": args.rename_retries,
+        "compress_at_rest": args.compress_at_rest.map(Compression::name),
+        "no_response_compression": args.no_response_compression,
+        "dedup": args.dedup,
+        "response_key": args.response_key,
+        "log_repeat_interval": args.log_repeat_interval,
+        "metrics": args.metrics,
+        "enable_admin": args.enable_admin,
+        "admin_token": args.admin_token.as_ref().map(|_| "<redacted>"),
+        "upload_secret": args.upload_secret.as_ref().map(|_| "<redacted>"),
+        "admin_token_file": args.admin_token_file,
+        "on_upload_cmd": args.on_upload_cmd,
+        "event_socket": args.event_socket,
+        "max_client_time_skew": args.max_client_time_skew,
+        "favicon": args.favicon,
+        "debug_delay": args.debug_delay,
+    })
+}
+
+struct AppState {
+    save_dir: PathBuf,
+    /// The temporary save directory without `--save-dir`, removed on drop.
+    spool: Option<TempDir>,
+    mirror_dir: Option<PathBuf>,
+    objects: Option<Box<dyn ObjectStore>>,
+    /// Lowercase extension to the `save_dir` subdirectory it is stored in.
+    ext_dirs: HashMap<String, PathBuf>,
+    name_template: Option<NameTemplate>,
+    /// Form field name to the sanitized filename its file is stored under.
+    field_paths: HashMap<String, String>,
+    reject_unmapped_fields: bool,
+    unnamed_files: UnnamedFiles,
+    mode: Option<Permissions>,
+    dir_mode: Option<Permissions>,
+    shard_depth: usize,
+    owner: Option<Owner>,
+    owner_best_effort: bool,
+    on_upload_cmd: Option<PathBuf>,
+    events: Option<EventSocket>,
+    max_client_time_skew: u64,
+    favicon: Option<Favicon>,
+    /// Recent log events for `GET /admin/logs`, kept with `--enable-admin`.
+    logs: Option<LogStream>,
+    /// Failures of the upload path, with repeats collapsed.
+    errors: Arc<RepeatLog>,
+    metrics: Option<Metrics>,
+    compression: Option<Compression>,
+    max_fields: usize,
+    max_form_value: usize,
+    max_request_size: usize,
+    part_limits: PartLimits,
+    verify_length: bool,
+    /// `--xattr` attributes as name and value.
+    xattrs: Vec<(String, Vec<u8>)>,
+    xattr_metadata: bool,
+    /// Set once a filesystem turned out not to support extended attributes.
+    xattr_unsupported: AtomicBool,
+    strip_exif: bool,
+    phash: bool,
+    thumbnail: Option<Dimensions>,
+    validate_json: bool,
+    validate_yaml: bool,
+    /// Whether uploads are identified, which needs a loadable magic database.
+    libmagic: bool,
+    allowed_magic_types: Option<GlobSet>,
+    case_insensitive_conflict: bool,
+    reject_dotfiles: bool,
+    /// Built from `--deny-name`, `--max-dimensions` and `--validation-file`.
+    validation: RwLock<Arc<ValidationPolicy>>,
+    upload_secret: Option<Vec<u8>>,
+    collect_rejections: bool,
+    partial_ok: bool,
+    keep_partial: bool,
+    parallel_fields: usize,
+    /// Bounds concurrent chmod and chown calls on uploaded files.
+    ownership_ops: Semaphore,
+    rename_retries: u32,
+    min_free_inodes: Option<u64>,
+    max_rate: Option<u64>,
+    total_rate: Option<Mutex<RateLimiter>>,
+    /// SHA-256 of stored content to the path holding it, when `--dedup` is set.
+    dedup_index: Option<Mutex<HashMap<String, PathBuf>>>,
+    /// Renames applied to upload response keys, including per-file ones.
+    response_keys: HashMap<String, String>,
+    /// Accepted admin bearer tokens, replaced when the token file is reloaded.
+    admin_tokens: RwLock<Vec<String>>,
+    /// Effective configuration served by `GET /admin/config`.
+    config: serde_json::Value,
+    /// Notified by `POST /admin/shutdown`.
+    shutdown: Notify,
+    /// `--debug-delay`, slept after an upload was stored.
+    debug_delay: Option<Duration>,
+    /// Set once shutdown starts; new uploads are refused from then on.
+    draining: AtomicBool,
+}
+
+#[derive(Clone)]
+struct Owner {
+    spec: String,
+    uid: Option<Uid>,
+    gid: Option<Gid>,
+}
+
+/// Compression applied to stored files with `--compress-at-rest`.
+#[derive(Clone, Copy)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(format!(
+                "unknown compression `{}`, expected gzip or zstd",
+                s
+            )),
+        }
+    }
+}
+
+impl Compression {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Gzip => "gz",
+            Self::Zstd => "zst",
+        }
+    }
+}
+
+/// Handling of parts that declare a Content-Type but no filename.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum UnnamedFiles {
+    /// Read them as form values, like any part without a filename.
+    Field,
+    Reject,
+    /// Store them under the field name, a UUID and an extension for the type.
+    Generate,
+}
+
+impl FromStr for UnnamedFiles {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "field" => Ok(Self::Field),
+            "reject" => Ok(Self::Reject),
+            "generate" => Ok(Self::Generate),
+            _ => Err(format!(
+                "unknown policy `{}`, expected field, reject or generate",
+                s
+            )),
+        }
+    }
+}
+
+impl UnnamedFiles {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Field => "field",
+            Self::Reject => "reject",
+            Self::Generate => "generate",
+        }
+    }
+}
+
+impl AppState {
+    /// The validation policy in effect, replaced when it is reloaded.
+    fn policy(&self) -> Arc<ValidationPolicy> {
+        self.validation.read().unwrap().clone()
+    }
+
+    /// Refuses uploads while a storage directory has fewer than `--min-free-inodes`
+    /// free, since each file needs one however much space is left.
+    ///
+    /// Filesystems that allocate inodes dynamically report none and are not checked.
+    async fn check_inodes(&self) -> Result<(), ApiError> {
+        let Some(reserve) = self.min_free_inodes else {
+            return Ok(());
+        };
+        for dir in std::iter::once(&self.save_dir).chain(&self.mirror_dir) {
+            let path = dir.clone();
+            let stat = match task::spawn_blocking(move || statvfs(&path)).await.unwrap() {
+                Ok(stat) => stat,
+                Err(e) => {
+                    tracing::warn!("Failed to check free inodes of {:?}: {}", dir, e);
+                    continue;
+                }
+            };
+            if stat.files() > 0 && (stat.files_available() as u64) < reserve {
+                self.errors.error(format!(
+                    "{:?} has {} free inodes, below the reserve of {}",
+                    dir,
+                    stat.files_available(),
+                    reserve
+                ));
+                return Err(ApiError::InsufficientStorage);
+            }
+        }
+        Ok(())
+    }
+
+    /// Where `--mirror-dir` keeps its copy of the stored `filepath`.
+    fn mirror_path(&self, filepath: &Path) -> Option<PathBuf> {
+        let dir = self.mirror_dir.as_ref()?;
+        Some(dir.join(filepath.strip_prefix(&self.save_dir).ok()?))
+    }
+
+    /// Whether stored files may go in directories that do not exist yet.
+    fn nests_files(&self) -> bool {
+        self.name_template.is_some() || self.shard_depth > 0
+    }
+
+    /// Path a sanitized filename is stored under, including any `--route-ext`
+    /// subdirectory, `--name-template`, `--shard-depth` directories and
+    /// compression suffix.
+    fn stored_path(&self, name: &NameMeta) -> PathBuf {
+        let extension = Path::new(&name.filename)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        let dir = match extension.and_then(|ext| self.ext_dirs.get(&ext)) {
+            Some(subdir) => self.save_dir.join(subdir),
+            None => self.save_dir.clone(),
+        };
+        let path = match &self.name_template {
+            Some(template) => dir.join(naming::render_name(template, name)),
+            None => dir.join(&name.filename),
+        };
+        let path = match self.shard_depth {
+            0 => path,
+            depth => naming::shard(&path, depth),
+        };
+        match self.compression {
+            Some(c) => {
+                let mut path = path.into_os_string();
+                path.push(format!(".{}", c.extension()));
+                PathBuf::from(path)
+            }
+            None => path,
+        }
+    }
+}
+
+/// Creates `filepath` for writing, compressing on the fly when configured.
+///
+/// Callers must `shutdown` the writer so compressed streams are finished.
+async fn create_writer(
+    filepath: &Path,
+    compression: Option<Compression>,
+) -> std::io::Result<Box<dyn AsyncWrite + Send + Unpin>> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(filepath)
+        .await?;
+    Ok(match compression {
+        Some(Compression::Gzip) => Box::new(GzipEncoder::new(file)),
+        Some(Compression::Zstd) => Box::new(ZstdEncoder::new(file)),
+        None => Box::new(file),
+    })
+}
+
+/// Size on disk of a stored file, reported only when it is compressed.
+async fn compressed_size(filepath: &Path, state: &AppState) -> Option<u64> {
+    state.compression?;
+    fs::metadata(filepath).await.ok().map(|m| m.len())
+}
+
+fn parse_mode(mode_str: &str) -> Result<Permissions> {
+    match u32::from_str_radix(mode_str, 8) {
+        Ok(mode) => Ok(Permissions::from_mode(mode)),
+        Err(e) => Err(anyhow!("invalid mode `{}`: {}", mode_str, e)),
+    }
+}
+
+/// Resolves an owner given as `user`, `user:group` or `:group`.
+fn parse_owner(spec: &str) -> Result<Owner> {
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None),
+    };
+    let uid = match user {
+        "" => None,
+        name => Some(resolve_uid(name)?),
+    };
+    let gid = match group {
+        None | Some("") => None,
+        Some(name) => Some(resolve_gid(name)?),
+    };
+    if uid.is_none() && gid.is_none() {
+        return Err(anyhow!("invalid owner `{}`", spec));
+    }
+    Ok(Owner {
+        spec: spec.to_string(),
+        uid,
+        gid,
+    })
+}
+
+fn resolve_uid(name: &str) -> Result<Uid> {
+    if let Some(id) = numeric_id(name, "uid")? {
+        return Ok(Uid::from_raw(id));
+    }
+    match User::from_name(name)? {
+        Some(user) => Ok(user.uid),
+        None => Err(anyhow!("unknown user `{}`", name)),
+    }
+}
+
+fn resolve_gid(name: &str) -> Result<Gid> {
+    if let Some(id) = numeric_id(name, "gid")? {
+        return Ok(Gid::from_raw(id));
+    }
+    match Group::from_name(name)? {
+        Some(group) => Ok(group.gid),
+        None => Err(anyhow!("unknown group `{}`", name)),
+    }
+}
+
+/// Parses an all-digit owner part as a raw id, so no passwd or group entry is needed.
+///
+/// The maximum value is rejected: chown takes it to mean "leave unchanged".
+fn numeric_id(name: &str, kind: &str) -> Result<Option<u32>> {
+    if !name.bytes().all(|b| b.is_ascii_digit()) {
+        return Ok(None);
+    }
+    match name.parse::<u32>() {
+        Ok(id) if id != u32::MAX => Ok(Some(id)),
+        _ => Err(anyhow!("{} `{}` is out of range", kind, name)),
+    }
+}
+
+/// Switches the process to the given user and group, refusing to go on as root.
+fn drop_privileges(target: &Owner) -> Result<()> {
+    let Some(uid) = target.uid else {
+        return Err(anyhow!("--run-as `{}` needs a user", target.spec));
+    };
+    let gid = match target.gid {
+        Some(gid) => gid,
+        None => match User::from_uid(uid)? {
+            Some(user) => user.gid,
+            None => return Err(anyhow!("--run-as `{}` needs a group", target.spec)),
+        },
+    };
+    if !uid.is_root() && getuid() == uid && geteuid() == uid && getgid() == gid && getegid() == gid
+    {
+        // Already switched, as a successor started by an upgrade is.
+        return Ok(());
+    }
+    setgroups(&[gid]).map_err(|e| anyhow!("Failed to set groups: {}", e))?;
+    setgid(gid).map_err(|e| anyhow!("Failed to set gid {}: {}", gid, e))?;
+    setuid(uid).map_err(|e| anyhow!("Failed to set uid {}: {}", uid, e))?;
+    if getuid() != uid || geteuid() != uid || getgid() != gid || getegid() != gid {
+        return Err(anyhow!("Failed to switch to {}", target.spec));
+    }
+    if !uid.is_root() && setuid(Uid::from_raw(0)).is_ok() {
+        return Err(anyhow!(
+            "still able to regain root after switching to {}",
+            target.spec
+        ));
+    }
+    println!("running as {}", target.spec);
+    Ok(())
+}
+
+/// Content type recorded for parts that do not declare one.
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Trailer carrying the expected digests of the uploaded files, comma-separated
+/// in upload order.
+const CHECKSUM_TRAILER: &str = "x-checksum-sha256";
+
+#[derive(Clone, Serialize)]
+struct SavedFile {
+    path: PathBuf,
+    filename: String,
+    content_type: String,
+    size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    declared_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compressed_size: Option<u64>,
+    sha256: String,
+    deduplicated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata_stripped: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_type: Option<FileType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    object: Option<StoredObject>,
+}
+
+/// Where a file was put with `--s3-bucket`.
+#[derive(Clone, Serialize)]
+struct StoredObject {
+    key: String,
+    url: String,
+}
+
+/// Stores the file parts of a multipart upload.
+///
+/// Everything that can refuse an upload without its body is checked first.
+/// hyper only answers `Expect: 100-continue` once the body is read, so
+/// clients waiting for it get the refusal instead and never send the body.
+async fn upload(
+    State(state): State<Arc<AppState>>,
+    Extension(trailers): Extension<Trailers>,
+    uri: Uri,
+    headers: HeaderMap,
+    mut request: Request,
+) -> Result<Response, ApiError> {
+    let times = UploadTimes::now(&headers, state.max_client_time_skew);
+    if state.draining.load(Ordering::Relaxed) {
+        return Err(ApiError::ShuttingDown);
+    }
+    let grant = UploadGrant::authorize(&state, &uri)?;
+    state.check_inodes().await?;
+    if let Some(sha256) = already_stored(&state, &headers).await {
+        // The body is left unread.
+        let etag = HeaderValue::from_str(&format!("\"{}\"", sha256)).unwrap();
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    if request.body().is_end_stream() {
+        return Err(ApiError::EmptyBody);
+    }
+    let declared = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+    if declared.is_some_and(|length| length > state.max_request_size as u64) {
+        return Err(ApiError::TooLarge);
+    }
+    let boundary = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_boundary)
+        .ok_or(ApiError::InvalidMultipart)?;
+    let body = std::mem::take(request.body_mut());
+    *request.body_mut() = Body::new(LimitedParts::new(body, boundary, state.part_limits));
+    let mut multipart = Multipart::from_request(request, &state)
+        .await
+        .map_err(|_| ApiError::InvalidMultipart)?;
+    let slots = Arc::new(Semaphore::new(state.parallel_fields));
+    let mut writes = JoinSet::new();
+    let mut form = serde_json::Map::new();
+    let mut rejected = Vec::new();
+    let read = read_fields(
+        &mut multipart,
+        &state,
+        grant.as_ref(),
+        &slots,
+        &mut writes,
+        &mut form,
+        &mut rejected,
+    )
+    .await;
+    // Writers are always awaited, so parts are complete or cleaned up even
+    // when reading the body failed.
+    let mut written = writes.join_all().await;
+    let incomplete = match read {
+        Ok(()) => None,
+        Err(e @ (ApiError::InvalidMultipart | ApiError::PartHeadersExceeded))
+            if state.partial_ok =>
+        {
+            Some(e)
+        }
+        Err(e) => {
+            // A failed request stores nothing, so parts already written go too.
+            for (_, _, saved) in &written {
+                if let Ok(file) = saved {
+                    remove_stored(&state, &file.path).await;
+                }
+            }
+            return Err(e);
+        }
+    };
+    written.sort_by_key(|(index, ..)| *index);
+    let mut saved_files = Vec::new();
+    let mut failure = None;
+    for (index, filename, saved) in written {
+        match saved {
+            Ok(saved) => saved_files.push(saved),
+            // The part the body broke off in, already discarded.
+            Err(ApiError::InvalidMultipart) if incomplete.is_some() => {}
+            Err(e) if e.is_rejection() => rejected.push((index, filename, e)),
+            Err(e) => {
+                failure.get_or_insert(e);
+            }
+        }
+    }
+    if let Some(e) = failure {
+        // As when reading the body fails, the parts stored alongside go too.
+        for file in &saved_files {
+            remove_stored(&state, &file.path).await;
+        }
+        return Err(e);
+    }
+    rejected.sort_by_key(|(index, ..)| *index);
+    let rejections: Vec<_> = rejected
+        .iter()
+        .map(|(_, filename, e)| Rejection::new(filename, e))
+        .collect();
+
+    if saved_files.is_empty() {
+        if let Some(e) = incomplete {
+            return Err(e);
+        }
+        if !rejections.is_empty() {
+            return Err(ApiError::FilesRejected(rejections));
+        }
+        return Err(ApiError::NoFiles);
+    }
+    if !state.collect_rejections && !rejected.is_empty() {
+        // Without --collect-rejections one rejected file fails the upload.
+        for file in &saved_files {
+            remove_stored(&state, &file.path).await;
+        }
+        return Err(rejected.swap_remove(0).2);
+    }
+    if let Some(expected) = trailers.get(CHECKSUM_TRAILER).await {
+        let expected: Vec<_> = expected.split(',').map(str::trim).collect();
+        let matched = expected.len() == saved_files.len()
+            && saved_files
+                .iter()
+                .zip(&expected)
+                .all(|(file, digest)| file.sha256.eq_ignore_ascii_case(digest));
+        if !matched {
+            for file in &saved_files {
+                remove_stored(&state, &file.path).await;
+            }
+            return Err(ApiError::ChecksumMismatch);
+        }
+    }
+    // Only an accepted upload reaches the bucket.
+    if let Err(e) = put_objects(&state, &mut saved_files).await {
+        return Err(storage_error(e, &state));
+    }
+    for saved in &saved_files {
+        log_saved(saved, &times);
+    }
+    if let Some(cmd) = &state.on_upload_cmd {
+        let fields = serde_json::Value::Object(form.clone()).to_string();
+        for saved in &saved_files {
+            tokio::spawn(run_upload_cmd(
+                cmd.clone(),
+                saved.clone(),
+                fields.clone(),
+                state.errors.clone(),
+            ));
+        }
+    }
+    if let Some(events) = &state.events {
+        for saved in &saved_files {
+            events.upload(saved, &form, &times);
+        }
+    }
+    if let Some(delay) = state.debug_delay {
+        tokio::time::sleep(delay).await;
+    }
+    let paths: Vec<_> = saved_files.iter().map(|f| &f.path).collect();
+    let mut response = json!({"saved_files": paths, "files": saved_files, "fields": form });
+    if !rejections.is_empty() {
+        response["rejected"] = json!(rejections);
+    }
+    if let Some(e) = incomplete {
+        response["incomplete"] = json!({"error": e.code(), "message": e.message()});
+    }
+    if !state.response_keys.is_empty() {
+        rename_keys(&mut response, &state.response_keys);
+    }
+    Ok(Json(response).into_response())
+}
+
+/// The digest in `If-None-Match` that is already stored, when `--dedup` is set.
+///
+/// Entity tags may be quoted, weak or a comma-separated list; `*` is not supported
+/// since it would match any upload.
+async fn already_stored(state: &AppState, headers: &HeaderMap) -> Option<String> {
+    let index = state.dedup_index.as_ref()?;
+    let tags = headers.get(header::IF_NONE_MATCH)?.to_str().ok()?;
+    for tag in tags.split(',') {
+        let tag = tag.trim();
+        let sha256 = tag.strip_prefix("W/").unwrap_or(tag).trim_matches('"');
+        let sha256 = sha256.to_ascii_lowercase();
+        let existing = index.lock().unwrap().get(&sha256).cloned();
+        if let Some(existing) = existing
+            && fs::try_exists(&existing).await.unwrap_or(false)
+        {
+            return Some(sha256);
+        }
+    }
+    None
+}
+
+/// Answers requests to `route` still running after `secs` with 504.
+fn with_timeout(
+    route: MethodRouter<Arc<AppState>>,
+    secs: Option<u64>,
+) -> MethodRouter<Arc<AppState>> {
+    match secs {
+        Some(secs) => route.layer(TimeoutLayer::with_status_code(
+            StatusCode::GATEWAY_TIMEOUT,
+            Duration::from_secs(secs),
+        )),
+        None => route,
+    }
+}
+
+/// Parses `--route-timeout` specs, as route=seconds.
+fn parse_route_timeouts(specs: &[String]) -> Result<HashMap<String, u64>> {
+    let mut timeouts = HashMap::new();
+    for spec in specs {
+        let parsed = spec
+            .split_once('=')
+            .and_then(|(route, secs)| Some((route, secs.parse::<u64>().ok()?)));
+        let Some((route, secs)) = parsed else {
+            return Err(anyhow!(
+                "invalid route timeout `{}`, expected route=seconds",
+                spec
+            ));
+        };
+        if secs == 0 {
+            return Err(anyhow!("--route-timeout for {} must be at least 1", route));
+        }
+        if timeouts.insert(route.to_string(), secs).is_some() {
+            return Err(anyhow!("route {} has more than one --route-timeout", route));
+        }
+    }
+    Ok(timeouts)
+}
+
+/// Parses `--route-ext` specs into a map from lowercase extension to subdirectory.
+fn parse_ext_routes(specs: &[String]) -> Result<HashMap<String, PathBuf>> {
+    let mut routes = HashMap::new();
+    for spec in specs {
+        let Some((extensions, dir)) = spec.split_once('=') else {
+            return Err(anyhow!(
+                "invalid extension route `{}`, expected ext,...=dir",
+                spec
+            ));
+        };
+        let dir = PathBuf::from(dir);
+        let relative = dir
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)));
+        if dir.as_os_str().is_empty() || !relative {
+            return Err(anyhow!(
+                "invalid extension route `{}`, the directory must be relative to --save-dir",
+                spec
+            ));
+        }
+        for ext in extensions.split(',') {
+            let ext = ext.trim().trim_start_matches('.').to_lowercase();
+            if ext.is_empty() {
+                return Err(anyhow!(
+                    "invalid extension route `{}`, empty extension",
+                    spec
+                ));
+            }
+            if routes.insert(ext.clone(), dir.clone()).is_some() {
+                return Err(anyhow!("extension `{}` is routed more than once", ext));
+            }
+        }
+    }
+    Ok(routes)
+}
+
+/// Parses `--field-path` specs into a map from form field name to filename.
+fn parse_field_paths(specs: &[String], reject_dotfiles: bool) -> Result<HashMap<String, String>> {
+    let mut paths = HashMap::new();
+    for spec in specs {
+        let Some((field, name)) = spec.split_once('=').filter(|(field, _)| !field.is_empty())
+        else {
+            return Err(anyhow!(
+                "invalid field path `{}`, expected field=name",
+                spec
+            ));
+        };
+        let name = sanitize_filename(name, reject_dotfiles).map_err(|_| {
+            anyhow!(
+                "invalid field path `{}`, `{}` is not a filename",
+                spec,
+                name
+            )
+        })?;
+        if paths.insert(field.to_string(), name).is_some() {
+            return Err(anyhow!("field `{}` is mapped more than once", field));
+        }
+    }
+    Ok(paths)
+}
+
+fn parse_key_rename(spec: &str) -> Result<(String, String)> {
+    match spec.split_once('=') {
+        Some((old, new)) if !old.is_empty() && !new.is_empty() => {
+            Ok((old.to_string(), new.to_string()))
+        }
+        _ => Err(anyhow!("invalid response key `{}`, expected old=new", spec)),
+    }
+}
+
+/// Renames keys of the response object and of the objects in its arrays.
+fn rename_keys(response: &mut serde_json::Value, renames: &HashMap<String, String>) {
+    let Some(object) = response.as_object_mut() else {
+        return;
+    };
+    for value in object.values_mut() {
+        for item in value.as_array_mut().into_iter().flatten() {
+            rename_keys(item, renames);
+        }
+    }
+    *object = std::mem::take(object)
+        .into_iter()
+        .map(|(key, value)| match renames.get(&key) {
+            Some(new) => (new.clone(), value),
+            None => (key, value),
+        })
+        .collect();
+}
+
+#[derive(Deserialize)]
+struct VerifyRequest {
+    name: String,
+    sha256: String,
+}
+
+/// Recomputes the digest of a stored file and compares it with the expected one.
+async fn verify(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<VerifyRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let (name, filepath) = match &state.name_template {
+        // Rendered names cannot be mapped back, so the client sends the
+        // stored path relative to the save directory instead.
+        Some(_) => {
+            let name = req.name.trim_matches('/');
+            let mut filepath = state.save_dir.clone();
+            for component in name.split('/') {
+                filepath.push(sanitize_filename(component, state.reject_dotfiles)?);
+            }
+            (name.to_string(), filepath)
+        }
+        None => {
+            let name = sanitize_filename(&req.name, state.reject_dotfiles)?;
+            let filepath = state.stored_path(&NameMeta::new(&name));
+            (name, filepath)
+        }
+    };
+    if !filepath.is_file() {
+        return Err(ApiError::NotFound);
+    }
+    let digest = match sha256_stored(&filepath, state.compression).await {
+        Ok(digest) => digest,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err(ApiError::NotFound),
+        Err(e) => {
+            state
+                .errors
+                .error(format!("Failed to hash {:?}: {}", filepath, e));
+            return Err(ApiError::StorageUnavailable);
+        }
+    };
+    let matched = digest.eq_ignore_ascii_case(req.sha256.trim());
+    Ok(Json(
+        json!({"name": name, "sha256": digest, "match": matched }),
+    ))
+}
+
+/// Turns a client-supplied filename into a single safe path component.
+///
+/// Control characters are stripped; NUL bytes, path separators and `.`/`..`
+/// are rejected, as are all names starting with `.` under `reject_dotfiles`.
+fn sanitize_filename(filename: &str, reject_dotfiles: bool) -> Result<String, ApiError> {
+    if filename.contains('\0') {
+        return Err(ApiError::InvalidFilename);
+    }
+    let name: String = filename.chars().filter(|c| !c.is_ascii_control()).collect();
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(ApiError::InvalidFilename);
+    }
+    if reject_dotfiles && name.starts_with('.') {
+        return Err(ApiError::InvalidFilename);
+    }
+    Ok(name)
+}
+
+/// Decodes an RFC 5987 `filename*=UTF-8''...` parameter of a part's
+/// Content-Disposition, which takes precedence over a plain `filename`.
+///
+/// The multipart parser only understands the plain form and skips a part
+/// announced with `filename*` alone, as if it were no file.
+fn encoded_filename(headers: &HeaderMap) -> Result<Option<String>, ApiError> {
+    let Some(disposition) = headers
+        .get(header::CONTENT_DISPOSITION)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Ok(None);
+    };
+    let Some(value) = disposition.split(';').find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("filename*")
+            .then(|| value.trim())
+    }) else {
+        return Ok(None);
+    };
+    // charset'language'percent-encoded-value
+    let mut parts = value.splitn(3, '\'');
+    let (Some(charset), Some(_language), Some(encoded)) =
+        (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ApiError::InvalidFilename);
+    };
+    if !charset.eq_ignore_ascii_case("utf-8") {
+        return Err(ApiError::InvalidFilename);
+    }
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail
+                .get(..2)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .ok_or(ApiError::InvalidFilename)?;
+            bytes.push(hex::decode(hex).map_err(|_| ApiError::InvalidFilename)?[0]);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes)
+        .map(Some)
+        .map_err(|_| ApiError::InvalidFilename)
+}
+
+/// Opens a stored file for reading its original content, decompressing it if needed.
+async fn open_stored(
+    filepath: &Path,
+    compression: Option<Compression>,
+) -> std::io::Result<Box<dyn AsyncRead + Send + Unpin>> {
+    let file = BufReader::new(File::open(filepath).await?);
+    Ok(match compression {
+        Some(Compression::Gzip) => Box::new(GzipDecoder::new(file)),
+        Some(Compression::Zstd) => Box::new(ZstdDecoder::new(file)),
+        None => Box::new(file),
+    })
+}
+
+/// Hashes the original content of a stored file.
+async fn sha256_stored(
+    filepath: &Path,
+    compression: Option<Compression>,
+) -> std::io::Result<String> {
+    let mut reader = open_stored(filepath, compression).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match reader.read(&mut buf).await? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Reads a stored file whole if it starts like an image; other files are not
+/// read past their header.
+async fn read_stored_image(filepath: &Path, state: &AppState) -> Option<Vec<u8>> {
+    let read = async {
+        let mut reader = open_stored(filepath, state.compression).await?;
+        let mut data = Vec::new();
+        (&mut reader).take(32).read_to_end(&mut data).await?;
+        if !phash::is_image(&data) {
+            return Ok(None);
+        }
+        reader.read_to_end(&mut data).await?;
+        Ok::<_, std::io::Error>(Some(data))
+    };
+    match read.await {
+        Ok(data) => data,
+        Err(e) => {
+            tracing::warn!("Failed to read image {:?}: {}", filepath, e);
+            None
+        }
+    }
+}
+
+/// Perceptual hash of a stored image with `--phash`.
+async fn phash_stored(filepath: &Path, state: &AppState) -> Option<String> {
+    if !state.phash {
+        return None;
+    }
+    let data = read_stored_image(filepath, state).await?;
+    task::spawn_blocking(move || phash::perceptual_hash(&data))
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Stores the thumbnail of a stored image with `--thumbnail`, returning its path.
+///
+/// Images that fail to decode or encode only get a warning; the upload
+/// itself stays stored.
+async fn thumbnail_stored(filepath: &Path, state: &AppState) -> Option<PathBuf> {
+    let size = state.thumbnail?;
+    let data = read_stored_image(filepath, state).await?;
+    let encoded = match task::spawn_blocking(move || thumbnail::thumbnail(&data, size)).await {
+        Ok(Ok(encoded)) => encoded,
+        Ok(Err(e)) => {
+            tracing::warn!("Failed to make a thumbnail of {:?}: {}", filepath, e);
+            return None;
+        }
+        Err(_) => return None,
+    };
+    let path = thumbnail::path_for(filepath);
+    let temp = temp_path(&path);
+    let stored = async {
+        fs::write(&temp, encoded).await?;
+        finalize_file(&temp, state).await?;
+        rename_file(&temp, &path, state).await?;
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    if let Err(e) = stored {
+        tracing::warn!("Failed to store thumbnail {:?}: {:#}", path, e);
+        remove_partial(&temp).await;
+        return None;
+    }
+    Some(path)
+}
+
+/// Hashes the regular files already in `dir` so earlier uploads can be deduplicated.
+///
+/// `--route-ext` subdirectories are included once they exist.
+async fn build_dedup_index(
+    dir: &Path,
+    subdirs: &[PathBuf],
+    shard_depth: usize,
+    compression: Option<Compression>,
+) -> Result<HashMap<String, PathBuf>> {
+    let mut index = HashMap::new();
+    index_dir(dir, shard_depth, compression, &mut index).await?;
+    for subdir in subdirs {
+        match index_dir(&dir.join(subdir), shard_depth, compression, &mut index).await {
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            result => result?,
+        }
+    }
+    Ok(index)
+}
+
+/// Indexes the files in `dir` and, up to `shard_depth` levels down, in its
+/// `--shard-depth` directories, by the hash of their original content.
+async fn index_dir(
+    dir: &Path,
+    shard_depth: usize,
+    compression: Option<Compression>,
+    index: &mut HashMap<String, PathBuf>,
+) -> std::io::Result<()> {
+    let mut dirs = vec![(dir.to_path_buf(), shard_depth)];
+    while let Some((dir, depth)) = dirs.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let file_type = entry.file_type().await?;
+            if file_type.is_file() && !is_temp_file(&name) && !thumbnail::is_thumbnail(&name) {
+                // Files stored without the current --compress-at-rest don't decode.
+                match sha256_stored(&entry.path(), compression).await {
+                    Ok(digest) => {
+                        index.insert(digest, entry.path());
+                    }
+                    Err(e) => {
+                        tracing::warn!("Not indexing {:?} for --dedup: {}", entry.path(), e)
+                    }
+                }
+            } else if file_type.is_dir() && depth > 0 && naming::is_shard(&name) {
+                dirs.push((entry.path(), depth - 1));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Picks a path in `dir` that does not exist yet by inserting a millisecond
+/// timestamp and a random token between the stem and extension of `base`.
+fn unique_name(dir: &Path, base: &str) -> PathBuf {
+    let base = Path::new(base);
+    let stem = base.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = match base.extension() {
+        Some(ext) => format!(".{}", ext.to_string_lossy()),
+        None => String::new(),
+    };
+    loop {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let token: u32 = rand::random();
+        let path = dir.join(format!("{}-{}-{:08x}{}", stem, millis, token, extension));
+        if !path.exists() {
+            return path;
+        }
+    }
+}
+
+/// Hidden `.part` files are uploads still being written.
+fn is_temp_file(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(".part")
+}
+
+/// Directory in the save directory that `--keep-partial` moves failed uploads to.
+const FAILED_DIR: &str = ".failed";
+
+/// Temporary path an upload to `filepath` is written to before being renamed.
+fn temp_path(filepath: &Path) -> PathBuf {
+    let name = filepath.file_name().unwrap_or_default().to_string_lossy();
+    unique_name(
+        filepath.parent().unwrap_or(Path::new(".")),
+        &format!(".{}.part", name),
+    )
+}
+
+/// Replaces a freshly written `filepath` with a hard link to stored content
+/// with the same digest, returning whether the upload was deduplicated.
+async fn link_duplicate(state: &AppState, sha256: &str, filepath: &Path) -> bool {
+    let Some(index) = &state.dedup_index else {
+        return false;
+    };
+    let existing = index.lock().unwrap().get(sha256).cloned();
+    if let Some(existing) = existing {
+        let link = temp_path(filepath);
+        let linked = match fs::hard_link(&existing, &link).await {
+            Ok(()) => fs::rename(&link, filepath).await,
+            Err(e) => Err(e),
+        };
+        match linked {
+            Ok(()) => return true,
+            Err(e) => {
+                tracing::warn!("Failed to link {:?} to {:?}: {}", filepath, existing, e);
+                let _ = fs::remove_file(&link).await;
+            }
+        }
+    }
+    index
+        .lock()
+        .unwrap()
+        .insert(sha256.to_string(), filepath.to_path_buf());
+    false
+}
+
+/// Removes the files next to a freshly stored `filepath` whose names differ
+/// from it only by case, so they are replaced like a file of the same name.
+///
+/// This lists the whole directory on every upload.
+async fn remove_case_variants(filepath: &Path, state: &AppState) {
+    let (Some(dir), Some(name)) = (filepath.parent(), filepath.file_name()) else {
+        return;
+    };
+    let name = name.to_string_lossy().to_lowercase();
+    let removed = async {
+        let stored = fs::metadata(filepath).await?;
+        let mut entries = fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let other = entry.file_name().to_string_lossy().into_owned();
+            if other.to_lowercase() != name || is_temp_file(&other) {
+                continue;
+            }
+            // A case-insensitive filesystem lists the stored file itself
+            // under whichever case it kept.
+            let metadata = entry.metadata().await?;
+            if !metadata.is_file()
+                || (metadata.dev(), metadata.ino()) == (stored.dev(), stored.ino())
+            {
+                continue;
+            }
+            let path = entry.path();
+            fs::remove_file(&path).await?;
+            if let Some(index) = &state.dedup_index {
+                index.lock().unwrap().retain(|_, stored| *stored != path);
+            }
+            if let Some(mirror) = state.mirror_path(&path) {
+                remove_partial(&mirror).await;
+            }
+            println!("replaced {:?}, which differs only by case", path);
+        }
+        Ok::<_, std::io::Error>(())
+    }
+    .await;
+    if let Err(e) = removed {
+        tracing::warn!("Failed to replace case variants of {:?}: {}", filepath, e);
+    }
+}
+
+/// Discards a stored file that failed verification after it was written.
+async fn remove_stored(state: &AppState, filepath: &Path) {
+    if let Some(index) = &state.dedup_index {
+        index.lock().unwrap().retain(|_, path| path != filepath);
+    }
+    discard_partial(state, filepath, filepath).await;
+    if let Some(mirror) = state.mirror_path(filepath) {
+        remove_partial(&mirror).await;
+    }
+    if state.thumbnail.is_some() {
+        remove_partial(&thumbnail::path_for(filepath)).await;
+    }
+}
+
+/// Puts the files of an accepted upload in `--s3-bucket`, all of them or,
+/// removing them again, none.
+///
+/// Without a `--save-dir` the local copies were only spooled for this, so
+/// they go once put and each file's `path` becomes its object key.
+async fn put_objects(state: &AppState, files: &mut [SavedFile]) -> Result<()> {
+    let Some(objects) = &state.objects else {
+        return Ok(());
+    };
+    let mut failed = None;
+    for file in files.iter_mut() {
+        let relative = file
+            .path
+            .strip_prefix(&state.save_dir)
+            .unwrap_or(&file.path);
+        let key = objectstore::key_for(relative);
+        match objects.put(&key, &file.path, &file.content_type).await {
+            Ok(url) => {
+                println!("put {:?} to {}", file.path, url);
+                file.object = Some(StoredObject { key, url });
+            }
+            Err(e) => {
+                failed = Some(e.context(format!("Failed to put {:?} in the bucket", file.path)));
+                break;
+            }
+        }
+    }
+    if let Some(e) = failed {
+        for file in files.iter() {
+            if let Some(object) = &file.object
+                && let Err(e) = objects.delete(&object.key).await
+            {
+                state.errors.error(format!("{:#}", e));
+            }
+            remove_stored(state, &file.path).await;
+        }
+        return Err(e);
+    }
+    if state.spool.is_some() {
+        for file in files.iter_mut() {
+            remove_partial(&file.path).await;
+            if let Some(object) = &file.object {
+                file.path = PathBuf::from(&object.key);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Outcome of writing one upload to disk.
+struct Stored {
+    /// Where the file ended up, which a `{sha256}` name template only gives
+    /// once the content is hashed.
+    path: PathBuf,
+    size: usize,
+    sha256: String,
+    metadata_stripped: Option<bool>,
+    file_type: Option<FileType>,
+}
+
+/// Streams chunks into a stored file while hashing and throttling them.
+///
+/// Chunks go to a hidden temporary file that only replaces the stored file
+/// once complete, so failed uploads never clobber it. With `--strip-exif`,
+/// uploads starting like a JPEG or PNG are held in memory instead and written
+/// once their metadata is removed.
+struct UploadWriter<'a> {
+    state: &'a AppState,
+    file: Box<dyn AsyncWrite + Send + Unpin>,
+    temp: PathBuf,
+    hasher: Sha256,
+    /// Bytes received from the client.
+    received: usize,
+    /// Bytes written to the file.
+    size: usize,
+    image: Option<Vec<u8>>,
+    rate: Option<RateLimiter>,
+    dimensions: Option<DimensionProbe>,
+    magic: Option<MagicProbe>,
+    /// Content kept to check it parses, with `--validate-json` or `--validate-yaml`.
+    document: Option<(Syntax, Vec<u8>)>,
+    /// Start of the content that libmagic identifies, with `--use-libmagic`.
+    type_head: Option<Vec<u8>>,
+    file_type: Option<FileType>,
+    max_size: Option<u64>,
+}
+
+impl<'a> UploadWriter<'a> {
+    async fn create(filepath: &Path, filename: &str, state: &'a AppState) -> Result<Self> {
+        if state.nests_files()
+            && let Some(dir) = filepath.parent()
+        {
+            create_dirs(dir, state).await?;
+        }
+        let temp = temp_path(filepath);
+        let mut created = create_writer(&temp, state.compression).await;
+        // An operator may have removed the save directory from under us.
+        if let Err(e) = &created
+            && e.kind() == ErrorKind::NotFound
+            && let Some(dir) = temp.parent()
+            && !dir.is_dir()
+        {
+            tracing::warn!("{:?} is gone, creating it again", dir);
+            create_dirs(dir, state).await?;
+            created = create_writer(&temp, state.compression).await;
+        }
+        let file = match created {
+            Ok(file) => file,
+            Err(e) => return Err(anyhow::Error::new(e).context("Failed to create file")),
+        };
+        let policy = state.policy();
+        Ok(Self {
+            state,
+            file,
+            temp,
+            hasher: Sha256::new(),
+            received: 0,
+            size: 0,
+            image: None,
+            rate: state.max_rate.map(RateLimiter::new),
+            dimensions: policy.max_dimensions.map(DimensionProbe::new),
+            magic: policy.magic_probe(filename),
+            document: Syntax::of(filename, state.validate_json, state.validate_yaml)
+                .map(|syntax| (syntax, Vec::new())),
+            type_head: state.libmagic.then(Vec::new),
+            file_type: None,
+            max_size: policy.max_size,
+        })
+    }
+
+    /// Lowers the size limit of this upload to `max_size` if it is higher.
+    fn limit_size(&mut self, max_size: u64) {
+        self.max_size = Some(self.max_size.map_or(max_size, |max| max.min(max_size)));
+    }
+
+    async fn write(&mut self, chunk: &[u8]) -> Result<()> {
+        if let Some(max_size) = self.max_size
+            && (self.received + chunk.len()) as u64 > max_size
+        {
+            return Err(SizeExceeded { max_size }.into());
+        }
+        self.throttle(chunk.len()).await;
+        if let Some(probe) = &mut self.dimensions {
+            probe.feed(chunk)?;
+        }
+        if let Some(probe) = &mut self.magic {
+            probe.feed(chunk)?;
+        }
+        if let Some((_, content)) = &mut self.document {
+            content.extend_from_slice(chunk);
+        }
+        if let Some(head) = &mut self.type_head {
+            let wanted = filetype::HEAD_SIZE.saturating_sub(head.len());
+            head.extend_from_slice(&chunk[..wanted.min(chunk.len())]);
+        }
+        if self.received == 0 && self.state.strip_exif && exif::is_strippable(chunk) {
+            self.image = Some(Vec::new());
+        }
+        self.received += chunk.len();
+        match &mut self.image {
+            Some(image) => {
+                image.extend_from_slice(chunk);
+                Ok(())
+            }
+            None => self.write_out(chunk).await,
+        }
+    }
+
+    async fn throttle(&mut self, bytes: usize) {
+        let mut wait = match &mut self.rate {
+            Some(rate) => rate.reserve(bytes),
+            None => Duration::ZERO,
+        };
+        if let Some(total) = &self.state.total_rate {
+            wait = wait.max(total.lock().unwrap().reserve(bytes));
+        }
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    async fn write_out(&mut self, chunk: &[u8]) -> Result<()> {
+        if let Err(e) = self.file.write_all(chunk).await {
+            return Err(anyhow::Error::new(e).context("Failed to write file"));
+        }
+        self.hasher.update(chunk);
+        self.size += chunk.len();
+        Ok(())
+    }
+
+    /// Completes the file, applies mode and owner and moves it to the path
+    /// `name` is stored under.
+    async fn finish(mut self, name: &NameMeta) -> Result<Stored> {
+        let result = self.complete(name).await;
+        if result.is_err() {
+            discard_partial(self.state, &self.temp, &self.state.stored_path(name)).await;
+        }
+        let (path, metadata_stripped) = result?;
+        Ok(Stored {
+            path,
+            size: self.size,
+            sha256: hex::encode(self.hasher.finalize()),
+            metadata_stripped,
+            file_type: self.file_type,
+        })
+    }
+
+    /// Drops an unfinished upload to `filepath`, leaving any stored file untouched.
+    async fn discard(mut self, filepath: &Path) {
+        // Flushed so a kept partial holds everything received.
+        let _ = self.file.flush().await;
+        discard_partial(self.state, &self.temp, filepath).await;
+    }
+
+    async fn complete(&mut self, name: &NameMeta) -> Result<(PathBuf, Option<bool>)> {
+        if let Some(probe) = &self.magic {
+            probe.finish()?;
+        }
+        if let Some((syntax, content)) = self.document.take() {
+            task::spawn_blocking(move || syntax.check(&content)).await??;
+        }
+        if let Some(head) = self.type_head.take() {
+            self.file_type = identify_type(head, &name.filename, self.state).await?;
+        }
+        let metadata_stripped = match self.image.take() {
+            Some(image) => {
+                let image = Bytes::from(image);
+                let original = image.clone();
+                let stripped = match task::spawn_blocking(move || exif::strip_metadata(image)).await
+                {
+                    Ok(Ok(stripped)) => Some(stripped),
+                    Ok(Err(e)) => {
+                        tracing::warn!("Failed to strip metadata from {:?}: {}", name.filename, e);
+                        None
+                    }
+                    Err(_) => None,
+                };
+                self.write_out(stripped.as_ref().unwrap_or(&original))
+                    .await?;
+                Some(stripped.is_some())
+            }
+            None => self.state.strip_exif.then_some(false),
+        };
+        if let Err(e) = self.file.shutdown().await {
+            return Err(anyhow::Error::new(e).context("Failed to write file"));
+        }
+        let hashed = name.with_sha256(hex::encode(self.hasher.clone().finalize()));
+        let filepath = &self.state.stored_path(&hashed);
+        // The hashed name may belong in another shard than the temporary file.
+        if self.state.shard_depth > 0
+            && let Some(dir) = filepath.parent()
+        {
+            create_dirs(dir, self.state).await?;
+        }
+        // Mode and owner go on before the rename, so the file never shows up
+        // in the save directory with the wrong ones.
+        finalize_file(&self.temp, self.state).await?;
+        xattr::apply(
+            &self.temp,
+            &name.filename,
+            self.file_type.as_ref(),
+            self.state,
+        )
+        .await?;
+        if let Err(e) = rename_file(&self.temp, filepath, self.state).await {
+            return Err(anyhow!("Failed to replace {:?}: {}", filepath, e));
+        }
+        if let Some(index) = &self.state.dedup_index {
+            index.lock().unwrap().retain(|_, path| path != filepath);
+        }
+        if self.state.case_insensitive_conflict {
+            remove_case_variants(filepath, self.state).await;
+        }
+        if let Err(e) = mirror_file(filepath, self.state).await {
+            remove_partial(filepath).await;
+            return Err(e);
+        }
+        Ok((filepath.clone(), metadata_stripped))
+    }
+}
+
+/// Identifies an upload with libmagic, failing it if `--allow-magic-type` is
+/// set and the type is not among them.
+///
+/// A file libmagic fails on is stored without a type, unless types are
+/// enforced.
+async fn identify_type(
+    head: Vec<u8>,
+    filename: &str,
+    state: &AppState,
+) -> Result<Option<FileType>> {
+    let detected = match task::spawn_blocking(move || filetype::identify(&head)).await? {
+        Ok(detected) => Some(detected),
+        Err(e) => {
+            tracing::warn!("Failed to identify {:?}: {:#}", filename, e);
+            None
+        }
+    };
+    if let Some(allowed) = &state.allowed_magic_types
+        && !detected
+            .as_ref()
+            .is_some_and(|detected| allowed.is_match(&detected.mime))
+    {
+        return Err(TypeNotAllowed {
+            mime: detected.map(|detected| detected.mime),
+        }
+        .into());
+    }
+    Ok(detected)
+}
+
+/// A file part of an upload request, as announced by its headers.
+struct FilePart {
+    name: NameMeta,
+    filename: String,
+    content_type: String,
+    declared_length: Option<u64>,
+    /// Size limit of an upload grant, on top of the validation policy's.
+    max_size: Option<u64>,
+}
+
+/// Data handed from the request body to the task writing a file part.
+enum Piece {
+    Chunk(Bytes),
+    /// The part was read completely; without it the writer discards the file.
+    End,
+}
+
+/// Chunks buffered between reading a part and writing it.
+const PIECES_IN_FLIGHT: usize = 8;
+
+/// Reads the multipart body, handing each file part to a writer task.
+///
+/// At most `--parallel-fields` writers run at once, so the next part is read
+/// off the network while earlier ones are still being written.
+///
+/// Nothing here needs the request's Content-Length, so chunked bodies are
+/// handled the same: size limits are enforced on the bytes as they arrive,
+/// and a part's own Content-Length is only checked in advance when sent.
+///
+/// Parts turned away before their writer starts go to `rejected` by part
+/// index instead of failing the upload, which is left to the caller.
+async fn read_fields(
+    multipart: &mut Multipart,
+    state: &Arc<AppState>,
+    grant: Option<&UploadGrant>,
+    slots: &Arc<Semaphore>,
+    writes: &mut JoinSet<(usize, String, Result<SavedFile, ApiError>)>,
+    form: &mut serde_json::Map<String, serde_json::Value>,
+    rejected: &mut Vec<(usize, String, ApiError)>,
+) -> Result<(), ApiError> {
+    let mut fields = 0;
+    let mut files = 0;
+    let failed = Arc::new(AtomicBool::new(false));
+    while let Some(mut field) = multipart.next_field().await? {
+        fields += 1;
+        if fields > state.max_fields {
+            return Err(ApiError::TooManyFields);
+        }
+        let named = match encoded_filename(field.headers())? {
+            Some(encoded) => Some(encoded),
+            None => field.file_name().map(str::to_string),
+        };
+        let generated;
+        let mut head = None;
+        let filename = match named.as_deref() {
+            Some(filename) => filename,
+            // Browsers send text fields without a Content-Type, so a part with
+            // one is a file its client did not name.
+            None if field.content_type().is_some()
+                && state.unnamed_files != UnnamedFiles::Field =>
+            {
+                if state.unnamed_files == UnnamedFiles::Reject {
+                    return Err(ApiError::MissingFilename);
+                }
+                generated = generated_filename(field.name(), field.content_type());
+                &generated
+            }
+            None => match read_form_value(&mut field, state.max_form_value).await? {
+                FormValue::Text(value) => {
+                    if let Some(name) = field.name() {
+                        form.insert(name.to_string(), value.into());
+                    }
+                    continue;
+                }
+                // Too long to hold, so the rest streams to disk like a file.
+                FormValue::Spilled(start) if state.unnamed_files == UnnamedFiles::Generate => {
+                    head = Some(start);
+                    generated = generated_filename(field.name(), field.content_type());
+                    &generated
+                }
+                FormValue::Spilled(_) => return Err(ApiError::FieldTooLarge),
+            },
+        };
+        // A grant allows one file, under the name it was signed for.
+        if grant.is_some() && files > 0 {
+            return Err(ApiError::TooManyFields);
+        }
+        let client_name = filename.to_string();
+        let (filename, declared_length) = match check_part(&field, filename, state, grant) {
+            Ok(checked) => checked,
+            Err(e) if e.is_rejection() => {
+                rejected.push((files, client_name, e));
+                files += 1;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let content_type = field
+            .content_type()
+            .unwrap_or(DEFAULT_CONTENT_TYPE)
+            .to_string();
+        let max_size = grant.map(|grant| grant.max_size);
+        let expected_length = declared_length.filter(|_| state.verify_length);
+        let part = FilePart {
+            name: NameMeta::new(&filename),
+            filename,
+            content_type,
+            declared_length,
+            max_size,
+        };
+        let permit = slots.clone().acquire_owned().await.unwrap();
+        if failed.load(Ordering::Relaxed) {
+            // Like a sequential upload, no part is started after one failed.
+            break;
+        }
+        let (tx, rx) = mpsc::channel(PIECES_IN_FLIGHT);
+        let index = files;
+        files += 1;
+        let writer_state = state.clone();
+        let failed = failed.clone();
+        writes.spawn(
+            async move {
+                let saved = store_part(&writer_state, part, rx).await;
+                if let Err(e) = &saved
+                    && !e.is_rejection()
+                {
+                    failed.store(true, Ordering::Relaxed);
+                }
+                drop(permit);
+                (index, client_name, saved)
+            }
+            .in_current_span(),
+        );
+        // Should the writer fail, its error is reported once it is joined.
+        feed_field(field, head, &tx, expected_length).await?;
+    }
+    Ok(())
+}
+
+/// Checks a file part's name and declared length against the upload's rules,
+/// returning the name it is stored under and its declared length.
+fn check_part(
+    field: &Field<'_>,
+    filename: &str,
+    state: &AppState,
+    grant: Option<&UploadGrant>,
+) -> Result<(String, Option<u64>), ApiError> {
+    let filename = match grant {
+        Some(grant) => sanitize_filename(&grant.name, state.reject_dotfiles)?,
+        None => match field.name().and_then(|name| state.field_paths.get(name)) {
+            Some(mapped) => mapped.clone(),
+            None if state.reject_unmapped_fields => return Err(ApiError::UnmappedField),
+            None => sanitize_filename(filename, state.reject_dotfiles)?,
+        },
+    };
+    let declared_length = match field.headers().get(header::CONTENT_LENGTH) {
+        Some(value) => match value.to_str().ok().and_then(|v| v.parse().ok()) {
+            Some(length) => Some(length),
+            None if state.verify_length => return Err(ApiError::InvalidMultipart),
+            None => None,
+        },
+        None => None,
+    };
+    state.policy().validate(&FieldMeta {
+        filename: &filename,
+        declared_length,
+    })?;
+    if let (Some(grant), Some(length)) = (grant, declared_length)
+        && length > grant.max_size
+    {
+        return Err(ApiError::FileTooLarge);
+    }
+    Ok((filename, declared_length))
+}
+
+/// Names an unnamed file part `<field>-<uuid>.<ext>`, with the extension
+/// taken from its Content-Type when it is a well-known one.
+fn generated_filename(field: Option<&str>, content_type: Option<&str>) -> String {
+    let field: String = field
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect();
+    let field = if field.is_empty() { "upload" } else { &field };
+    let essence = content_type
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase());
+    let extension = match essence.as_deref() {
+        Some("image/jpeg") => Some("jpg"),
+        Some("image/png") => Some("png"),
+        Some("image/gif") => Some("gif"),
+        Some("image/webp") => Some("webp"),
+        Some("application/pdf") => Some("pdf"),
+        Some("application/json") => Some("json"),
+        Some("application/zip") => Some("zip"),
+        Some("text/plain") => Some("txt"),
+        Some("text/csv") => Some("csv"),
+        Some("application/octet-stream") => Some("bin"),
+        _ => None,
+    };
+    let uuid = naming::random_uuid();
+    match extension {
+        Some(ext) => format!("{}-{}.{}", field, uuid, ext),
+        None => format!("{}-{}", field, uuid),
+    }
+}
+
+enum FormValue {
+    Text(String),
+    /// More than `--max-form-value` bytes, of which these were read.
+    Spilled(Bytes),
+}
+
+/// Reads a form field without a filename as UTF-8 text of at most
+/// `max_size` bytes, stopping once it turns out longer.
+async fn read_form_value(field: &mut Field<'_>, max_size: usize) -> Result<FormValue, ApiError> {
+    let mut value = Vec::new();
+    while let Some(chunk) = field.chunk().await? {
+        value.extend_from_slice(&chunk);
+        if value.len() > max_size {
+            return Ok(FormValue::Spilled(value.into()));
+        }
+    }
+    String::from_utf8(value)
+        .map(FormValue::Text)
+        .map_err(|_| ApiError::InvalidMultipart)
+}
+
+/// Sends a part's chunks to its writer, `head` first if part of the part was
+/// already read, until the part ends or the writer gives up.
+///
+/// With `expected_length` set, the part must be exactly that many bytes.
+async fn feed_field(
+    mut field: Field<'_>,
+    mut head: Option<Bytes>,
+    tx: &mpsc::Sender<Piece>,
+    expected_length: Option<u64>,
+) -> Result<(), ApiError> {
+    let mut received = 0;
+    loop {
+        let chunk = match head.take() {
+            Some(chunk) => chunk,
+            None => match field.chunk().await? {
+                Some(chunk) => chunk,
+                None => break,
+            },
+        };
+        received += chunk.len() as u64;
+        if expected_length.is_some_and(|n| received > n) {
+            return Err(ApiError::LengthMismatch);
+        }
+        if tx.send(Piece::Chunk(chunk)).await.is_err() {
+            return Ok(());
+        }
+    }
+    if expected_length.is_some_and(|n| received != n) {
+        return Err(ApiError::LengthMismatch);
+    }
+    let _ = tx.send(Piece::End).await;
+    Ok(())
+}
+
+/// Logs a file an upload stored, for the audit trail.
+fn log_saved(saved: &SavedFile, times: &UploadTimes) {
+    if saved.deduplicated {
+        println!(
+            "linked {:?} to existing content, {}",
+            saved.path,
+            times.audit()
+        );
+    } else {
+        println!("saved to {:?}, {}", saved.path, times.audit());
+    }
+}
+
+/// Writes one file part as its chunks arrive, discarding the partial file on failure.
+async fn store_part(
+    state: &AppState,
+    part: FilePart,
+    mut rx: mpsc::Receiver<Piece>,
+) -> Result<SavedFile, ApiError> {
+    let filepath = state.stored_path(&part.name);
+    let mut writer = UploadWriter::create(&filepath, &part.filename, state)
+        .await
+        .map_err(|e| storage_error(e, state))?;
+    if let Some(max_size) = part.max_size {
+        writer.limit_size(max_size);
+    }
+    loop {
+        match rx.recv().await {
+            Some(Piece::Chunk(chunk)) => {
+                if let Err(e) = writer.write(&chunk).await {
+                    writer.discard(&filepath).await;
+                    return Err(write_error(e, state));
+                }
+            }
+            Some(Piece::End) => break,
+            None => {
+                // Reading the body failed; that error is the one reported.
+                writer.discard(&filepath).await;
+                return Err(ApiError::InvalidMultipart);
+            }
+        }
+    }
+    let stored = writer
+        .finish(&part.name)
+        .await
+        .map_err(|e| write_error(e, state))?;
+    let filepath = stored.path;
+    let deduplicated = link_duplicate(state, &stored.sha256, &filepath).await;
+    let compressed_size = compressed_size(&filepath, state).await;
+    let phash = phash_stored(&filepath, state).await;
+    let thumbnail = thumbnail_stored(&filepath, state).await;
+    Ok(SavedFile {
+        compressed_size,
+        phash,
+        thumbnail,
+        object: None,
+        path: filepath,
+        filename: part.filename,
+        content_type: part.content_type,
+        size: stored.size,
+        declared_length: part.declared_length,
+        sha256: stored.sha256,
+        deduplicated,
+        metadata_stripped: stored.metadata_stripped,
+        file_type: stored.file_type,
+    })
+}
+
+/// Maps a failed write to a client error when the upload itself was refused.
+fn write_error(err: anyhow::Error, state: &AppState) -> ApiError {
+    if let Some(exceeded) = err.downcast_ref::<DimensionsExceeded>() {
+        state.errors.warn(format!("rejected upload: {}", exceeded));
+        return ApiError::ImageTooLarge;
+    }
+    if let Some(exceeded) = err.downcast_ref::<SizeExceeded>() {
+        state.errors.warn(format!("rejected upload: {}", exceeded));
+        return ApiError::FileTooLarge;
+    }
+    if let Some(reason) = err.downcast_ref::<RejectReason>() {
+        state.errors.warn(format!("rejected upload: {}", reason));
+        return (*reason).into();
+    }
+    if let Some(rejected) = err.downcast_ref::<TypeNotAllowed>() {
+        state.errors.warn(format!("rejected upload: {}", rejected));
+        return ApiError::TypeNotAllowed;
+    }
+    if let Some(malformed) = err.downcast_ref::<Malformed>() {
+        state.errors.warn(format!("rejected upload: {}", malformed));
+        return ApiError::MalformedContent(malformed.to_string());
+    }
+    storage_error(err, state)
+}
+
+/// Logs a storage failure, telling a full disk apart from other I/O errors.
+fn storage_error(err: anyhow::Error, state: &AppState) -> ApiError {
+    state.errors.error(format!("{:#}", err));
+    if is_storage_full(&err) {
+        ApiError::InsufficientStorage
+    } else {
+        ApiError::StorageUnavailable
+    }
+}
+
+fn is_storage_full(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == ErrorKind::StorageFull)
+    })
+}
+
+/// Deletes the file of a failed upload to `filepath`, or with `--keep-partial`
+/// moves it into the `.failed` directory for inspection.
+async fn discard_partial(state: &AppState, partial: &Path, filepath: &Path) {
+    if !state.keep_partial {
+        remove_partial(partial).await;
+        return;
+    }
+    if !partial.is_file() {
+        return;
+    }
+    let failed_dir = state.save_dir.join(FAILED_DIR);
+    let name = filepath.file_name().unwrap_or_default().to_string_lossy();
+    let kept = async {
+        fs::create_dir_all(&failed_dir).await?;
+        let target = unique_name(&failed_dir, &name);
+        fs::rename(partial, &target).await?;
+        Ok::<_, std::io::Error>(target)
+    }
+    .await;
+    match kept {
+        Ok(target) => tracing::warn!("kept failed upload as {:?}", target),
+        Err(e) => {
+            state
+                .errors
+                .error(format!("Failed to keep {:?}: {}", partial, e));
+            remove_partial(partial).await;
+        }
+    }
+}
+
+async fn remove_partial(filepath: &Path) {
+    if filepath.is_file()
+        && let Err(e) = fs::remove_file(filepath).await
+    {
+        tracing::error!("{}", e);
+    }
+}
+
+/// First wait before retrying a failed rename, doubled on every retry.
+const RENAME_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Moves a finished file into place, retrying up to `--rename-retries` times
+/// on the transient errors networked filesystems report.
+///
+/// Other errors, such as EXDEV for a target on another filesystem, fail
+/// straight away since retrying cannot fix them.
+async fn rename_file(from: &Path, to: &Path, state: &AppState) -> std::io::Result<()> {
+    let mut delay = RENAME_BACKOFF;
+    let mut retries = 0;
+    loop {
+        match fs::rename(from, to).await {
+            Err(e)
+                if retries < state.rename_retries
+                    && matches!(e.raw_os_error(), Some(libc::ESTALE | libc::EBUSY)) =>
+            {
+                tracing::warn!(
+                    "Failed to rename {:?} to {:?}: {}, retrying in {:?}",
+                    from,
+                    to,
+                    e,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Applies the configured file mode and owner to a fully written file.
+///
+/// Both run on the blocking pool, so at most `--max-ownership-ops` files are
+/// finalized at once to leave room for other blocking work.
+async fn finalize_file(filepath: &Path, state: &AppState) -> Result<()> {
+    if state.mode.is_none() && state.owner.is_none() {
+        return Ok(());
+    }
+    let _permit = state.ownership_ops.acquire().await?;
+    if let Some(m) = &state.mode
+        && let Err(e) = set_permissions(filepath, m.clone()).await
+    {
+        return Err(anyhow!("Failed to set permissions: {}", e));
+    }
+    apply_owner(filepath, state).await
+}
+
+/// Polls `/proc/mounts` until `mount_point` shows up, so nothing is written
+/// to the directory underneath a filesystem that is not mounted yet.
+async fn wait_for_mount(mount_point: &Path, timeout: Duration) -> Result<()> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut announced = false;
+    loop {
+        let mounts = fs::read_to_string("/proc/mounts").await?;
+        if mounts
+            .lines()
+            .filter_map(|line| line.split(' ').nth(1))
+            .any(|target| Path::new(&unescape_mount_path(target)) == mount_point)
+        {
+            return Ok(());
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(anyhow!(
+                "{:?} was not mounted within {}s",
+                mount_point,
+                timeout.as_secs()
+            ));
+        }
+        if !announced {
+            println!("waiting for {:?} to be mounted", mount_point);
+            announced = true;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Decodes the octal escapes `/proc/mounts` uses for spaces and similar bytes.
+fn unescape_mount_path(field: &str) -> String {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut rest = field.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..3)
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match escaped {
+            Some(decoded) if byte == b'\\' => {
+                bytes.push(decoded);
+                rest = &tail[3..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Creates the save directory and applies the configured directory mode and owner.
+async fn prepare_save_dir(state: &AppState) -> Result<()> {
+    let roots = std::iter::once(&state.save_dir).chain(&state.mirror_dir);
+    let dirs = roots.flat_map(|root| {
+        let subdirs = state.ext_dirs.values().map(|subdir| root.join(subdir));
+        std::iter::once(root.clone()).chain(subdirs)
+    });
+    for dir in &dirs.collect::<Vec<_>>() {
+        fs::create_dir_all(dir).await?;
+        finalize_dir(dir, state).await?;
+    }
+    Ok(())
+}
+
+/// Creates the directories missing up to `dir`, which a `--name-template`
+/// may place below the save directory or which were removed while running,
+/// with the directory mode and owner.
+async fn create_dirs(dir: &Path, state: &AppState) -> Result<()> {
+    let missing = dir
+        .ancestors()
+        .take_while(|dir| !dir.as_os_str().is_empty() && !dir.is_dir())
+        .collect::<Vec<_>>();
+    for dir in missing.into_iter().rev() {
+        match fs::create_dir(dir).await {
+            Ok(()) => finalize_dir(dir, state).await?,
+            // Another upload created it first.
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(anyhow!("Failed to create {:?}: {}", dir, e)),
+        }
+    }
+    Ok(())
+}
+
+async fn finalize_dir(dir: &Path, state: &AppState) -> Result<()> {
+    if let Some(m) = &state.dir_mode
+        && let Err(e) = set_permissions(dir, m.clone()).await
+    {
+        return Err(anyhow!("Failed to set permissions on {:?}: {}", dir, e));
+    }
+    apply_owner(dir, state).await
+}
+
+/// Places a copy of the stored `filepath` in `--mirror-dir`, hard-linked when
+/// both directories share a filesystem and written out otherwise.
+async fn mirror_file(filepath: &Path, state: &AppState) -> Result<()> {
+    let Some(target) = state.mirror_path(filepath) else {
+        return Ok(());
+    };
+    if state.nests_files()
+        && let Some(dir) = target.parent()
+    {
+        create_dirs(dir, state).await?;
+    }
+    let temp = temp_path(&target);
+    let placed = async {
+        match fs::hard_link(filepath, &temp).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+                fs::copy(filepath, &temp).await?;
+                finalize_file(&temp, state).await?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        rename_file(&temp, &target, state).await?;
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    if let Err(e) = placed {
+        remove_partial(&temp).await;
+        return Err(e.context(format!("Failed to mirror {:?} to {:?}", filepath, target)));
+    }
+    Ok(())
+}
+
+async fn apply_owner(path: &Path, state: &AppState) -> Result<()> {
+    let Some(owner) = &state.owner else {
+        return Ok(());
+    };
+    let target = path.to_path_buf();
+    let (uid, gid) = (owner.uid, owner.gid);
+    let Ok(result) = task::spawn_blocking(move || chown(&target, uid, gid)).await else {
+        return Err(anyhow!("Failed to execute chown"));
+    };
+    match result {
+        Ok(()) => {}
+        Err(Errno::EPERM) if state.owner_best_effort => {
+            tracing::warn!("chown to {} not permitted, keeping {:?}", owner.spec, path);
+        }
+        Err(e) => {
+            return Err(anyhow!(
+                "Failed to chown {:?} to {}: {}",
+                path,
+                owner.spec,
+                e
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Runs the `--on-upload-cmd` program for a saved file, logging its output.
+///
+/// `fields` is the JSON object of form values sent with the file.
+async fn run_upload_cmd(cmd: PathBuf, file: SavedFile, fields: String, errors: Arc<RepeatLog>) {
+    let mut command = Command::new(&cmd);
+    command
+        .arg(&file.path)
+        .env("PETGUARD_FILENAME", &file.filename)
+        .env("PETGUARD_CONTENT_TYPE", &file.content_type)
+        .env("PETGUARD_SIZE", file.size.to_string())
+        .env("PETGUARD_FIELDS", fields)
+        .env_remove(upgrade::LISTEN_FD_ENV)
+        .stdin(Stdio::null());
+    let output = match command.output().await {
+        Ok(output) => output,
+        Err(e) => {
+            errors.error(format!("Failed to execute {:?}: {}", cmd, e));
+            return;
+        }
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stdout.trim().is_empty() {
+        tracing::info!("{:?} stdout: {}", cmd, stdout.trim_end());
+    }
+    if !stderr.trim().is_empty() {
+        tracing::warn!("{:?} stderr: {}", cmd, stderr.trim_end());
+    }
+    if !output.status.success() {
+        errors.error(format!(
+            "{:?} failed for {:?}: {}",
+            cmd, file.path, output.status
+        ));
+    }
+}
+
+async fn test_handler() -> Html<&'static str> {
+    Html(
+        r##"
+    <!DOCTYPE html>
+    <html lang="en">
+        <head>
+            <meta charset="UTF-8">
+            <meta name="viewport" content="width=device-width, initial-scale=1.0">
+            <title>Hello World! Site Title</title>
+        </head>
+        <body>
+            <h1>Hello World!</h1>
+        </body>
+    </html>
+    "##,
+    )
+}
+
+/// Icon loaded from `--favicon` at startup.
+struct Favicon {
+    content_type: &'static str,
+    data: Bytes,
+}
+
+fn load_favicon(path: &Path) -> Result<Favicon> {
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase());
+    let content_type = match extension.as_deref() {
+        Some("ico") => "image/x-icon",
+        Some("png") => "image/png",
+        Some("svg") => "image/svg+xml",
+        _ => {
+            return Err(anyhow!(
+                "--favicon {:?} must be a .ico, .png or .svg file",
+                path
+            ));
+        }
+    };
+    let data = std::fs::read(path).map_err(|e| anyhow!("Failed to read {:?}: {}", path, e))?;
+    Ok(Favicon {
+        content_type,
+        data: data.into(),
+    })
+}
+
+/// Answers browsers' favicon requests, so using `/` does not log a 404 each time.
+async fn serve_favicon(State(state): State<Arc<AppState>>) -> Response {
+    match &state.favicon {
+        Some(icon) => (
+            [
+                (header::CONTENT_TYPE, icon.content_type),
+                (header::CACHE_CONTROL, "public, max-age=86400"),
+            ],
+            icon.data.clone(),
+        )
+            .into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+async fn handler_404() -> impl IntoResponse {
+    (StatusCode::NOT_FOUND, "nothing to see here")
+}
+
+/// Refuses requests whose request line and headers add up to more than
+/// `--max-header-size` bytes, before any body is read.
+///
+/// By the time it runs hyper has parsed the headers within its own buffer
+/// limit, set from `--max-header-size` too; this holds what slips under that,
+/// such as limits below hyper's minimum buffer, to the exact size and answers
+/// with the JSON error body.
+async fn limit_header_size(
+    State(max): State<usize>,
+    request: Request,
+    next: middleware::Next,
+) -> Result<Response, ApiError> {
+    // Counted as sent on the wire: "name: value\r\n" per header.
+    let size = request.method().as_str().len()
+        + request.uri().to_string().len()
+        + request
+            .headers()
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len() + 4)
+            .sum::<usize>();
+    if size > max {
+        tracing::warn!("rejected request with {} bytes of headers", size);
+        return Err(ApiError::HeadersTooLarge);
+    }
+    Ok(next.run(request).await)
+}
+
+/// Refuses requests expecting anything but `100-continue`, which hyper
+/// otherwise ignores, with `--strict-expect`.
+async fn reject_unknown_expect(
+    request: Request,
+    next: middleware::Next,
+) -> Result<Response, ApiError> {
+    let expected = request
+        .headers()
+        .get_all(header::EXPECT)
+        .iter()
+        .all(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+    if !expected {
+        return Err(ApiError::ExpectationFailed);
+    }
+    Ok(next.run(request).await)
+}
+
+fn request_span(request: &Request) -> tracing::Span {
+    let id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!("request", id = %id, method = %request.method(), uri = %request.uri())
+}
+
+async fn shutdown_signal(state: Arc<AppState>) {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+    tokio::select! {
+        _ = ctrl_c => println!("signal received, starting graceful shutdown"),
+        _ = terminate => println!("signal received, starting graceful shutdown"),
+        _ = state.shutdown.notified() => println!("shutdown requested, starting graceful shutdown"),
+    }
+    state.draining.store(true, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::ServiceExt;
+
+    #[test]
+    fn sanitize_filename_rejects_nul() {
+        assert_eq!(
+            sanitize_filename("cat\0.jpg", false),
+            Err(ApiError::InvalidFilename)
+        );
+        assert_eq!(
+            sanitize_filename("\0", false),
+            Err(ApiError::InvalidFilename)
+        );
+    }
+
+    #[test]
+    fn sanitize_filename_strips_control_chars() {
+        assert_eq!(
+            sanitize_filename("ca\x01t\x1b[0m.jpg\x7f", false).as_deref(),
+            Ok("cat[0m.jpg")
+        );
+        assert_eq!(
+            sanitize_filename("\r\n\t", false),
+            Err(ApiError::InvalidFilename)
+        );
+    }
+
+    #[test]
+    fn sanitize_filename_rejects_traversal() {
+        for name in ["", ".", "..", ".\x01.", "../cat.jpg", "a/../b"] {
+            assert_eq!(
+                sanitize_filename(name, false),
+                Err(ApiError::InvalidFilename),
+                "{:?}",
+                name
+            );
+        }
+        assert_eq!(sanitize_filename("..cat", false).as_deref(), Ok("..cat"));
+    }
+
+    #[test]
+    fn sanitize_filename_rejects_separators() {
+        for name in ["/etc/passwd", "dir/cat.jpg", "cat.jpg/", "/"] {
+            assert_eq!(
+                sanitize_filename(name, false),
+                Err(ApiError::InvalidFilename),
+                "{:?}",
+                name
+            );
+        }
+        // Only `/` separates paths here.
+        assert_eq!(
+            sanitize_filename("dir\\cat.jpg", false).as_deref(),
+            Ok("dir\\cat.jpg")
+        );
+    }
+
+    #[test]
+    fn sanitize_filename_dotfiles() {
+        assert_eq!(
+            sanitize_filename(".bashrc", false).as_deref(),
+            Ok(".bashrc")
+        );
+        assert_eq!(
+            sanitize_filename(".bashrc", true),
+            Err(ApiError::InvalidFilename)
+        );
+    }
+
+    #[test]
+    fn sanitize_filename_invalid_utf8() {
+        // Names reach sanitize_filename as UTF-8; bytes that aren't are
+        // refused while decoding, or replaced when decoded lossily.
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_DISPOSITION,
+            HeaderValue::from_static("form-data; name=\"f\"; filename*=UTF-8''cat%FF%FE.jpg"),
+        );
+        assert_eq!(encoded_filename(&headers), Err(ApiError::InvalidFilename));
+        let lossy = String::from_utf8_lossy(b"cat\xff\x00.jpg");
+        assert_eq!(
+            sanitize_filename(&lossy, false),
+            Err(ApiError::InvalidFilename)
+        );
+        let lossy = String::from_utf8_lossy(b"cat\xff.jpg");
+        assert_eq!(
+            sanitize_filename(&lossy, false).as_deref(),
+            Ok("cat\u{fffd}.jpg")
+        );
+    }
+
+    fn disposition(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_DISPOSITION, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn encoded_filename_decodes_utf8() {
+        let headers = disposition("form-data; name=\"f\"; filename*=UTF-8''%C3%A9t%C3%A9.jpg");
+        assert_eq!(
+            encoded_filename(&headers),
+            Ok(Some(String::from("été.jpg")))
+        );
+        // Charset and parameter name are case-insensitive; the language is ignored.
+        let headers = disposition("form-data; name=\"f\"; FILENAME*=utf-8'fr'chat%20%E2%9C%93.png");
+        assert_eq!(
+            encoded_filename(&headers),
+            Ok(Some(String::from("chat ✓.png")))
+        );
+        let headers = disposition("form-data; name=\"f\"; filename*=UTF-8''plain.txt");
+        assert_eq!(
+            encoded_filename(&headers),
+            Ok(Some(String::from("plain.txt")))
+        );
+    }
+
+    #[test]
+    fn encoded_filename_only_with_the_parameter() {
+        assert_eq!(encoded_filename(&HeaderMap::new()), Ok(None));
+        let headers = disposition("form-data; name=\"f\"; filename=\"cat.jpg\"");
+        assert_eq!(encoded_filename(&headers), Ok(None));
+    }
+
+    #[test]
+    fn encoded_filename_rejects_bad_encoding() {
+        for value in [
+            "form-data; filename*=ISO-8859-1''caf%E9.jpg",
+            "form-data; filename*=UTF-8'%C3%A9.jpg",
+            "form-data; filename*=%C3%A9.jpg",
+            "form-data; filename*=UTF-8''caf%C3",
+            "form-data; filename*=UTF-8''caf%G9.jpg",
+            "form-data; filename*=UTF-8''caf%+9.jpg",
+            "form-data; filename*=UTF-8''caf%",
+        ] {
+            assert_eq!(
+                encoded_filename(&disposition(value)),
+                Err(ApiError::InvalidFilename),
+                "{:?}",
+                value
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn unique_name_under_concurrent_creates() {
+        let dir = tempfile::tempdir().unwrap();
+        let creates = (0..64).map(|_| {
+            let dir = dir.path().to_path_buf();
+            tokio::spawn(async move {
+                let path = unique_name(&dir, "cat.jpg");
+                // Fails if another task already created the same path.
+                create_writer(&path, None).await.map(|_| path)
+            })
+        });
+        let mut paths = HashSet::new();
+        for create in creates.collect::<Vec<_>>() {
+            let path = create.await.unwrap().unwrap();
+            assert!(paths.insert(path));
+        }
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 64);
+    }
+
+    #[test]
+    fn unique_name_keeps_stem_and_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = unique_name(dir.path(), "cat.tar.gz");
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert_eq!(path.parent(), Some(dir.path()));
+        let (stem, rest) = name.split_once('-').unwrap();
+        assert_eq!(stem, "cat.tar");
+        let (millis, rest) = rest.split_once('-').unwrap();
+        assert!(millis.parse::<u128>().is_ok());
+        assert_eq!(rest.len(), "01234567.gz".len());
+        assert!(rest.ends_with(".gz"));
+        assert!(unique_name(dir.path(), "README").extension().is_none());
+    }
+
+    fn ids(spec: &str) -> (Option<u32>, Option<u32>) {
+        let owner = parse_owner(spec).unwrap();
+        (owner.uid.map(Uid::as_raw), owner.gid.map(Gid::as_raw))
+    }
+
+    #[test]
+    fn parse_owner_numeric_ids() {
+        assert_eq!(ids("1000"), (Some(1000), None));
+        assert_eq!(ids("1000:1001"), (Some(1000), Some(1001)));
+        assert_eq!(ids(":1001"), (None, Some(1001)));
+        assert_eq!(ids("1000:"), (Some(1000), None));
+        // Ids without a passwd or group entry are taken as they are.
+        assert_eq!(ids("4294967294"), (Some(4294967294), None));
+    }
+
+    #[test]
+    fn parse_owner_numeric_range() {
+        for spec in [
+            "4294967295",
+            "4294967296",
+            ":4294967295",
+            "1:99999999999999",
+        ] {
+            let Err(err) = parse_owner(spec) else {
+                panic!("{} parsed", spec);
+            };
+            assert!(
+                err.to_string().contains("out of range"),
+                "{}: {}",
+                spec,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn parse_owner_names() {
+        assert_eq!(ids("root"), (Some(0), None));
+        assert_eq!(ids("root:0"), (Some(0), Some(0)));
+        assert!(parse_owner("").is_err());
+        assert!(parse_owner(":").is_err());
+        assert!(parse_owner("no-such-petguard-user").is_err());
+        assert!(parse_owner("-1").is_err());
+    }
+
+    const BOUNDARY: &str = "petguard-test-boundary";
+
+    /// A router for `args`, saving to a fresh directory dropped with it.
+    async fn test_app(args: &[&str]) -> (Router, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut all = vec!["--save-dir", dir.path().to_str().unwrap()];
+        all.extend(args);
+        let config = Config::from_args(&["petguard"], &all).unwrap();
+        (build_router(config).await.unwrap(), dir)
+    }
+
+    /// One multipart part; a file part when `filename` is set.
+    fn part(name: &str, filename: Option<&str>, content: &[u8]) -> Vec<u8> {
+        let mut disposition = format!("form-data; name=\"{}\"", name);
+        if let Some(filename) = filename {
+            disposition.push_str(&format!("; filename=\"{}\"", filename));
+        }
+        raw_part(&disposition, content)
+    }
+
+    /// One multipart part with the Content-Disposition `disposition`.
+    fn raw_part(disposition: &str, content: &[u8]) -> Vec<u8> {
+        let mut part = format!(
+            "--{}\r\nContent-Disposition: {}\r\n\r\n",
+            BOUNDARY, disposition
+        )
+        .into_bytes();
+        part.extend_from_slice(content);
+        part.extend_from_slice(b"\r\n");
+        part
+    }
+
+    fn closing() -> Vec<u8> {
+        format!("--{}--\r\n", BOUNDARY).into_bytes()
+    }
+
+    fn upload_request(body: Body) -> Request {
+        Request::post("/upload")
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .body(body)
+            .unwrap()
+    }
+
+    async fn send(app: Router, request: Request) -> (StatusCode, serde_json::Value) {
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn form_fields_cannot_clobber_response_keys() {
+        let (app, dir) = test_app(&[]).await;
+        let body = [
+            part("error", None, b"oops"),
+            part("saved_files", None, b"[]"),
+            part("f", Some("cat.txt"), b"meow"),
+            closing(),
+        ]
+        .concat();
+        let (status, body) = send(app, upload_request(Body::from(body))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.get("error").is_none());
+        assert_eq!(body["fields"]["error"], "oops");
+        assert_eq!(body["fields"]["saved_files"], "[]");
+        let stored = dir.path().join("cat.txt");
+        assert_eq!(body["saved_files"], json!([stored]));
+        assert_eq!(std::fs::read(stored).unwrap(), b"meow");
+    }
+
+    #[tokio::test]
+    async fn encoded_filename_upload() {
+        let (app, dir) = test_app(&[]).await;
+        let body = [
+            raw_part(
+                "form-data; name=\"f\"; filename=\"ete.jpg\"; filename*=UTF-8''%C3%A9t%C3%A9.jpg",
+                b"summer",
+            ),
+            // Without a plain filename the part is still a file.
+            raw_part(
+                "form-data; name=\"g\"; filename*=UTF-8''%E7%8C%AB%01.txt",
+                b"meow",
+            ),
+            closing(),
+        ]
+        .concat();
+        let (status, body) = send(app, upload_request(Body::from(body))).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["files"][0]["filename"], "été.jpg");
+        assert_eq!(body["files"][1]["filename"], "猫.txt");
+        assert_eq!(
+            std::fs::read(dir.path().join("été.jpg")).unwrap(),
+            b"summer"
+        );
+        assert_eq!(std::fs::read(dir.path().join("猫.txt")).unwrap(), b"meow");
+    }
+
+    #[tokio::test]
+    async fn encoded_filename_upload_is_sanitized() {
+        let (app, dir) = test_app(&[]).await;
+        let body = [
+            raw_part(
+                "form-data; name=\"f\"; filename=\"cat.jpg\"; filename*=UTF-8''..%2F..%2Fcat.jpg",
+                b"meow",
+            ),
+            closing(),
+        ]
+        .concat();
+        let (status, body) = send(app, upload_request(Body::from(body))).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["rejected"][0]["error"], "invalid_filename");
+        assert!(files_in(dir.path()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn empty_body_and_no_files() {
+        let (app, _dir) = test_app(&[]).await;
+        let (status, body) = send(app.clone(), upload_request(Body::empty())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "empty_body");
+        let only_fields = [part("note", None, b"hi"), closing()].concat();
+        let (status, body) = send(app, upload_request(Body::from(only_fields))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "no_files");
+    }
+
+    #[tokio::test]
+    async fn every_file_rejected() {
+        // Rejections are listed whether or not they are collected.
+        for args in [&[][..], &["--collect-rejections"]] {
+            let (app, dir) = test_app(args).await;
+            let body = [
+                part("f", Some(".."), b"up"),
+                part("note", None, b"hi"),
+                part("g", Some("a/b.txt"), b"slash"),
+                closing(),
+            ]
+            .concat();
+            let (status, body) = send(app, upload_request(Body::from(body))).await;
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{:?}", args);
+            assert_eq!(body["error"], "files_rejected");
+            assert_eq!(
+                body["rejected"],
+                json!([
+                    {"filename": "..", "error": "invalid_filename", "message": "filename is not allowed"},
+                    {"filename": "a/b.txt", "error": "invalid_filename", "message": "filename is not allowed"},
+                ])
+            );
+            assert!(files_in(dir.path()).is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn some_files_rejected() {
+        let body = [
+            part("f", Some("cat.txt"), b"meow"),
+            part("g", Some(".."), b"up"),
+            closing(),
+        ]
+        .concat();
+        // Without --collect-rejections the upload fails for the one file.
+        let (app, dir) = test_app(&[]).await;
+        let (status, response) = send(app, upload_request(Body::from(body.clone()))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(response["error"], "invalid_filename");
+        assert!(files_in(dir.path()).is_empty());
+        let (app, dir) = test_app(&["--collect-rejections"]).await;
+        let (status, response) = send(app, upload_request(Body::from(body))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["rejected"][0]["filename"], "..");
+        assert_eq!(files_in(dir.path()), ["cat.txt"]);
+    }
+
+    #[tokio::test]
+    async fn header_heavy_part() {
+        let (app, dir) = test_app(&[]).await;
+        let headers = "X-Pad: a\r\n".repeat(5000);
+        let body = [
+            part("f", Some("cat.txt"), b"meow"),
+            raw_part(
+                &format!("form-data; name=\"g\"; filename=\"dog.txt\"\r\n{}", headers),
+                b"woof",
+            ),
+            closing(),
+        ]
+        .concat();
+        let (status, body) = send(app, upload_request(chunked(body, 1000))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "part_headers_exceeded");
+        assert!(files_in(dir.path()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_part_removes_its_siblings() {
+        let (app, dir) = test_app(&["--route-ext", "bin=bins"]).await;
+        // Files for bins/ can no longer be created.
+        let bins = dir.path().join("bins");
+        std::fs::remove_dir(&bins).unwrap();
+        std::fs::write(&bins, b"").unwrap();
+        let body = [
+            part("f", Some("one.txt"), b"first"),
+            part("g", Some("two.bin"), b"second"),
+            part("h", Some("three.txt"), b"third"),
+            closing(),
+        ]
+        .concat();
+        let (status, body) = send(app, upload_request(Body::from(body))).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{}", body);
+        assert_eq!(files_in(dir.path()), ["bins"]);
+    }
+
+    /// Two whole files, then a third cut off partway, closing boundary and all.
+    fn truncated_body() -> Vec<u8> {
+        let mut cut = part("h", Some("cut.bin"), &[7; 5000]);
+        cut.truncate(cut.len() - 1000);
+        [
+            part("f", Some("one.txt"), b"first"),
+            part("g", Some("two.txt"), b"second"),
+            cut,
+        ]
+        .concat()
+    }
+
+    #[tokio::test]
+    async fn truncated_upload_fails_whole() {
+        let (app, dir) = test_app(&[]).await;
+        let (status, body) = send(app, upload_request(chunked(truncated_body(), 512))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "invalid_multipart");
+        assert!(files_in(dir.path()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn truncated_upload_with_partial_ok() {
+        let (app, dir) = test_app(&["--partial-ok"]).await;
+        let (status, body) = send(app, upload_request(chunked(truncated_body(), 512))).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["incomplete"]["error"], "invalid_multipart");
+        let names: Vec<_> = body["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| file["filename"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["one.txt", "two.txt"]);
+        // The cut-off file and its temporary file are gone.
+        assert_eq!(files_in(dir.path()), ["one.txt", "two.txt"]);
+        assert_eq!(
+            std::fs::read(dir.path().join("two.txt")).unwrap(),
+            b"second"
+        );
+    }
+
+    #[tokio::test]
+    async fn trailing_garbage_with_partial_ok() {
+        let (app, dir) = test_app(&["--partial-ok"]).await;
+        let mut body = part("f", Some("one.txt"), b"first");
+        body.extend_from_slice(format!("--{}\r\ngarbage without end", BOUNDARY).as_bytes());
+        let (status, body) = send(app, upload_request(Body::from(body))).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["incomplete"]["error"], "invalid_multipart");
+        assert_eq!(files_in(dir.path()), ["one.txt"]);
+    }
+
+    /// A body sent in `size` byte chunks with no length known up front, as
+    /// with `Transfer-Encoding: chunked`.
+    fn chunked(body: Vec<u8>, size: usize) -> Body {
+        let chunks: Vec<_> = body
+            .chunks(size)
+            .map(|chunk| Ok::<_, std::io::Error>(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let body = Body::from_stream(futures_util::stream::iter(chunks));
+        assert_eq!(body.size_hint().exact(), None);
+        body
+    }
+
+    /// Names of the files in `dir`, hidden ones included.
+    fn files_in(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn chunked_upload_without_content_length() {
+        let (app, dir) = test_app(&["--max-request-size", "100000"]).await;
+        let content: Vec<u8> = (0..50_000).map(|i| (i % 251) as u8).collect();
+        let body = [part("f", Some("cat.bin"), &content), closing()].concat();
+        let (status, body) = send(app, upload_request(chunked(body, 7))).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["files"][0]["size"], 50_000);
+        assert_eq!(std::fs::read(dir.path().join("cat.bin")).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn chunked_upload_over_the_limit_while_streaming() {
+        let (app, dir) = test_app(&["--max-request-size", "10000"]).await;
+        let body = [part("f", Some("cat.bin"), &[0; 20_000]), closing()].concat();
+        let (status, body) = send(app, upload_request(chunked(body, 1000))).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error"], "too_large");
+        assert!(files_in(dir.path()).is_empty());
+    }
+}
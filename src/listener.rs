@@ -0,0 +1,353 @@
+use axum::{
+    Router,
+    extract::{ConnectInfo, Request},
+    middleware::Next,
+    response::Response,
+    serve::Listener,
+};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto::Builder, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::{Pin, pin},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{Instant, Sleep},
+};
+use tower::ServiceExt;
+use tower_http::normalize_path::NormalizePath;
+
+/// The smallest read buffer hyper accepts for an HTTP/1 connection.
+const MIN_BUF_SIZE: usize = 8192;
+
+/// Open connections by remote IP address.
+type IpCounts = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// A TCP listener that caps the number of open connections, overall and per
+/// remote IP address.
+///
+/// Connections accepted while a cap is reached are closed immediately, and
+/// with an idle timeout those that neither send nor receive anything for that
+/// long are closed too.
+pub struct LimitedListener {
+    inner: TcpListener,
+    slots: Option<Arc<Semaphore>>,
+    per_ip: Option<(usize, IpCounts)>,
+    idle_timeout: Option<Duration>,
+    open: Arc<AtomicUsize>,
+}
+
+impl LimitedListener {
+    pub fn new(
+        inner: TcpListener,
+        max_connections: Option<usize>,
+        max_per_ip: Option<usize>,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            inner,
+            slots: max_connections.map(|n| Arc::new(Semaphore::new(n))),
+            per_ip: max_per_ip.map(|n| (n, Arc::default())),
+            idle_timeout,
+            open: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Takes a connection slot for `ip`, or `None` if it has too many open.
+    fn acquire_ip_slot(&self, ip: IpAddr) -> Option<Option<IpSlot>> {
+        let Some((max, counts)) = &self.per_ip else {
+            return Some(None);
+        };
+        let mut open = counts.lock().unwrap();
+        let count = open.entry(ip).or_default();
+        if *count >= *max {
+            return None;
+        }
+        *count += 1;
+        Some(Some(IpSlot {
+            ip,
+            counts: counts.clone(),
+        }))
+    }
+
+    /// Counter of currently open connections.
+    pub fn open_connections(&self) -> Arc<AtomicUsize> {
+        self.open.clone()
+    }
+}
+
+impl Listener for LimitedListener {
+    type Io = LimitedStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            let (stream, addr) = Listener::accept(&mut self.inner).await;
+            let permit = match &self.slots {
+                Some(slots) => match slots.clone().try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        tracing::warn!("connection limit reached, rejecting {}", addr);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let Some(ip_slot) = self.acquire_ip_slot(addr.ip()) else {
+                tracing::warn!("per-IP connection limit reached, rejecting {}", addr);
+                continue;
+            };
+            self.open.fetch_add(1, Ordering::Relaxed);
+            let stream = LimitedStream {
+                inner: stream,
+                _permit: permit,
+                _ip_slot: ip_slot,
+                idle: self.idle_timeout.map(IdleTimer::new),
+                in_flight: InFlight::default(),
+                open: self.open.clone(),
+            };
+            return (stream, addr);
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+/// Serves `app` on `listener` until `signal` resolves, then waits for the
+/// open connections to finish their requests.
+///
+/// hyper holds header blocks to `max_header_size` bytes as it reads them:
+/// an HTTP/1 connection buffers no more, or the 8 KiB hyper needs at least,
+/// and answers a longer request head with 431, and HTTP/2 announces it as
+/// its header list limit. Oversized headers are thus refused unbuffered.
+pub async fn serve(
+    mut listener: LimitedListener,
+    app: NormalizePath<Router>,
+    max_header_size: usize,
+    signal: impl Future<Output = ()>,
+) {
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .max_buf_size(max_header_size.max(MIN_BUF_SIZE));
+    builder
+        .http2()
+        .max_header_list_size(u32::try_from(max_header_size).unwrap_or(u32::MAX));
+    let graceful = GracefulShutdown::new();
+    let mut signal = pin!(signal);
+    loop {
+        let (stream, _) = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = &mut signal => break,
+        };
+        let in_flight = stream.in_flight.clone();
+        let service = app
+            .clone()
+            .map_request(move |mut request: Request<Incoming>| {
+                request
+                    .extensions_mut()
+                    .insert(ConnectInfo(in_flight.clone()));
+                request
+            });
+        let connection = builder
+            .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service))
+            .into_owned();
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::debug!("connection failed: {}", e);
+            }
+        });
+    }
+    // Closes the socket, so new connections are refused while draining.
+    drop(listener);
+    graceful.shutdown().await;
+}
+
+/// A connection counted against its remote IP address until dropped.
+struct IpSlot {
+    ip: IpAddr,
+    counts: IpCounts,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut open = self.counts.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Fails a connection's I/O once it has been idle for the timeout.
+struct IdleTimer {
+    timeout: Duration,
+    last_active: Instant,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl IdleTimer {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_active: Instant::now(),
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+        }
+    }
+
+    /// Records that bytes were read or written.
+    fn touch(&mut self) {
+        self.last_active = Instant::now();
+    }
+
+    /// Called while I/O is pending; errors once nothing was read or written
+    /// for the timeout, and otherwise wakes the task when it may have been.
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        // Activity only moves `last_active`, so the sleep is reset when it
+        // fires rather than on every read.
+        while self.sleep.as_mut().poll(cx).is_ready() {
+            let deadline = self.last_active + self.timeout;
+            if deadline <= Instant::now() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "connection idle for too long",
+                ));
+            }
+            self.sleep.as_mut().reset(deadline);
+        }
+        Ok(())
+    }
+}
+
+/// A TCP stream holding its connection slots until dropped.
+pub struct LimitedStream {
+    inner: TcpStream,
+    _permit: Option<OwnedSemaphorePermit>,
+    _ip_slot: Option<IpSlot>,
+    idle: Option<IdleTimer>,
+    in_flight: InFlight,
+    open: Arc<AtomicUsize>,
+}
+
+impl LimitedStream {
+    /// Applies the idle timeout to the outcome of a read or write.
+    fn track_idle<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        let Some(idle) = &mut self.idle else {
+            return poll;
+        };
+        // hyper keeps reading while a handler runs, which is not idling.
+        let busy = self.in_flight.0.load(Ordering::Relaxed) > 0;
+        match poll {
+            Poll::Pending if !busy => match idle.poll_expired(cx) {
+                Ok(()) => Poll::Pending,
+                Err(e) => Poll::Ready(Err(e)),
+            },
+            poll => {
+                idle.touch();
+                poll
+            }
+        }
+    }
+}
+
+impl Drop for LimitedStream {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AsyncRead for LimitedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.track_idle(cx, poll)
+    }
+}
+
+impl AsyncWrite for LimitedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.track_idle(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        self.track_idle(cx, poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+/// The number of requests being handled on a connection, which keep its idle
+/// timeout from running.
+///
+/// Handed to each of the connection's requests as connect info by
+/// [`serve`], and counted by [`track_in_flight`].
+#[derive(Clone, Default)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+/// Counts a request as in flight on its connection until its response is ready.
+pub async fn track_in_flight(request: Request, next: Next) -> Response {
+    let Some(ConnectInfo(in_flight)) = request.extensions().get::<ConnectInfo<InFlight>>().cloned()
+    else {
+        return next.run(request).await;
+    };
+    in_flight.0.fetch_add(1, Ordering::Relaxed);
+    let _done = InFlightGuard(in_flight);
+    next.run(request).await
+}
+
+/// Ends a request's count when dropped, also if the handler is cancelled.
+struct InFlightGuard(InFlight);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        (self.0).0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::AppState;
+use axum::{
+    extract::State,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::{Stream, StreamExt, stream};
+use serde_json::{Map, Value, json};
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
+use tracing::{
+    Subscriber,
+    field::{Field, Visit},
+};
+use tracing_subscriber::{Layer, layer::Context};
+
+/// Log events kept for clients that connect to `GET /admin/logs` later.
+const HISTORY: usize = 256;
+
+/// A tracing layer that keeps the latest events as JSON and broadcasts new
+/// ones to `GET /admin/logs` subscribers.
+///
+/// Subscribers that fall behind skip the events they missed instead of
+/// making the buffer grow.
+#[derive(Clone)]
+pub struct LogStream {
+    history: Arc<Mutex<VecDeque<String>>>,
+    sender: broadcast::Sender<String>,
+}
+
+impl LogStream {
+    pub fn new() -> Self {
+        Self {
+            history: Arc::new(Mutex::new(VecDeque::with_capacity(HISTORY))),
+            sender: broadcast::channel(HISTORY).0,
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for LogStream {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut fields = FieldMap(Map::new());
+        event.record(&mut fields);
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let line = json!({
+            "time_ms": time,
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "fields": fields.0,
+        })
+        .to_string();
+        let mut history = self.history.lock().unwrap();
+        if history.len() == HISTORY {
+            history.pop_front();
+        }
+        history.push_back(line.clone());
+        // Nobody may be listening; the line is still kept in the history.
+        let _ = self.sender.send(line);
+    }
+}
+
+/// Collects the fields of an event, `message` included, into a JSON object.
+struct FieldMap(Map<String, Value>);
+
+impl Visit for FieldMap {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+}
+
+/// Streams the buffered log events, then new ones as they are logged, as
+/// server-sent events.
+pub async fn logs(
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let logs = state
+        .logs
+        .as_ref()
+        .expect("/admin/logs is only routed with a log stream");
+    // Subscribing first means nothing logged in between is lost, though a
+    // line may be sent twice.
+    let receiver = logs.sender.subscribe();
+    let history = logs.history.lock().unwrap().clone();
+    let backlog = stream::iter(history).map(|line| Ok(Event::default().data(line)));
+    let live = stream::unfold(receiver, |mut receiver| async move {
+        let event = match receiver.recv().await {
+            Ok(line) => Event::default().data(line),
+            // Tells the client how many events it missed for being slow.
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                Event::default().event("lagged").data(skipped.to_string())
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        };
+        Some((Ok(event), receiver))
+    });
+    Sse::new(backlog.chain(live)).keep_alive(KeepAlive::default())
+}
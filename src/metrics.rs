@@ -0,0 +1,149 @@
+use crate::AppState;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{Method, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Upper bounds in seconds of the request duration histogram buckets.
+const BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 30.0];
+
+/// Request counts and latencies by method, route template and status class,
+/// served from `GET /metrics` in the Prometheus text format with `--metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<Labels, Histogram>>,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Labels {
+    method: &'static str,
+    route: String,
+    status: &'static str,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    fn observe(&self, labels: Labels, seconds: f64) {
+        let mut requests = self.requests.lock().unwrap();
+        let histogram = requests.entry(labels).or_default();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    fn render(&self) -> String {
+        let requests = self.requests.lock().unwrap();
+        let mut out = String::new();
+        out.push_str("# HELP petguard_http_requests_total HTTP requests answered.\n");
+        out.push_str("# TYPE petguard_http_requests_total counter\n");
+        for (labels, histogram) in requests.iter() {
+            let _ = writeln!(
+                out,
+                "petguard_http_requests_total{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+        out.push_str(
+            "# HELP petguard_http_request_duration_seconds Time until the response headers were ready.\n",
+        );
+        out.push_str("# TYPE petguard_http_request_duration_seconds histogram\n");
+        for (labels, histogram) in requests.iter() {
+            let name = "petguard_http_request_duration_seconds";
+            for (count, bound) in histogram.buckets.iter().zip(BUCKETS) {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    name, labels, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"+Inf\"}} {}",
+                name, labels, histogram.count
+            );
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+        }
+        out
+    }
+}
+
+impl std::fmt::Display for Labels {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Route templates come from our own routes, so need no escaping.
+        write!(
+            f,
+            "method=\"{}\",route=\"{}\",status=\"{}\"",
+            self.method, self.route, self.status
+        )
+    }
+}
+
+/// Records the method, matched route and status class of every request.
+///
+/// Requests no route matched share the `unmatched` route, and unusual
+/// methods are counted as `OTHER`, so clients cannot grow the label sets.
+pub async fn track(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let Some(metrics) = &state.metrics else {
+        return next.run(request).await;
+    };
+    let method = match *request.method() {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::OPTIONS => "OPTIONS",
+        _ => "OTHER",
+    };
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string());
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let status = match response.status().as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    };
+    metrics.observe(
+        Labels {
+            method,
+            route,
+            status,
+        },
+        started.elapsed().as_secs_f64(),
+    );
+    response
+}
+
+/// Serves the collected metrics.
+pub async fn serve(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let body = state
+        .metrics
+        .as_ref()
+        .map(Metrics::render)
+        .unwrap_or_default();
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
@@ -0,0 +1,263 @@
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A `--name-template` such as `{date}/{uuid}-{orig}` or `{sha256}.{ext}`,
+/// giving the path of an upload relative to its save directory.
+///
+/// Placeholders are `{date}` (UTC, YYYY-MM-DD), `{uuid}` (random v4),
+/// `{orig}` (the sanitized filename), `{stem}` and `{ext}` (its parts around
+/// the last dot) and `{sha256}` (the stored content's digest). `/` in the
+/// template creates subdirectories; `{sha256}` may only appear after the last
+/// one, since the directory is needed before the content is hashed.
+#[derive(Clone)]
+pub struct NameTemplate {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Copy)]
+enum Placeholder {
+    Date,
+    Uuid,
+    Orig,
+    Stem,
+    Ext,
+    Sha256,
+}
+
+#[derive(Clone)]
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+impl FromStr for NameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while !rest.is_empty() {
+            let literal_end = rest.find(['{', '}']).unwrap_or(rest.len());
+            if literal_end > 0 {
+                parts.push(Part::Literal(rest[..literal_end].to_string()));
+            }
+            rest = &rest[literal_end..];
+            if rest.starts_with('}') {
+                return Err(format!("unmatched `}}` in name template `{}`", s));
+            }
+            let Some(after) = rest.strip_prefix('{') else {
+                break;
+            };
+            let Some((name, after)) = after.split_once('}') else {
+                return Err(format!("unclosed `{{` in name template `{}`", s));
+            };
+            let placeholder = match name {
+                "date" => Placeholder::Date,
+                "uuid" => Placeholder::Uuid,
+                "orig" => Placeholder::Orig,
+                "stem" => Placeholder::Stem,
+                "ext" => Placeholder::Ext,
+                "sha256" => Placeholder::Sha256,
+                _ => {
+                    return Err(format!(
+                        "unknown placeholder `{{{}}}` in name template",
+                        name
+                    ));
+                }
+            };
+            parts.push(Part::Placeholder(placeholder));
+            rest = after;
+        }
+        let template = Self {
+            source: s.to_string(),
+            parts,
+        };
+        template.check()?;
+        Ok(template)
+    }
+}
+
+impl fmt::Display for NameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl NameTemplate {
+    /// Rejects templates that could leave the save directory or need the
+    /// digest to pick a directory.
+    fn check(&self) -> Result<(), String> {
+        let sample = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(text) => text.as_str(),
+                Part::Placeholder(_) => "x",
+            })
+            .collect::<String>();
+        if sample.is_empty() || sample.ends_with('/') {
+            return Err(format!("name template `{}` names no file", self.source));
+        }
+        if !Path::new(&sample)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(format!(
+                "name template `{}` must be a relative path without `.` or `..`",
+                self.source
+            ));
+        }
+        let hashed_dir = self
+            .parts
+            .iter()
+            .skip_while(|part| !matches!(part, Part::Placeholder(Placeholder::Sha256)))
+            .any(|part| matches!(part, Part::Literal(text) if text.contains('/')));
+        if hashed_dir {
+            return Err(format!(
+                "`{{sha256}}` may only appear in the file name of name template `{}`",
+                self.source
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The values a name template is rendered from.
+///
+/// The date and UUID are fixed when the upload starts, so rendering again
+/// once the digest is known picks the same directory.
+#[derive(Clone)]
+pub struct NameMeta {
+    pub filename: String,
+    date: String,
+    uuid: String,
+    sha256: Option<String>,
+}
+
+impl NameMeta {
+    pub fn new(filename: &str) -> Self {
+        let days = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / 86_400;
+        let (year, month, day) = civil_from_days(days as i64);
+        Self {
+            filename: filename.to_string(),
+            date: format!("{:04}-{:02}-{:02}", year, month, day),
+            uuid: random_uuid(),
+            sha256: None,
+        }
+    }
+
+    /// The same name once the stored content hashes to `sha256`.
+    pub fn with_sha256(&self, sha256: String) -> Self {
+        Self {
+            sha256: Some(sha256),
+            ..self.clone()
+        }
+    }
+}
+
+/// A random version 4 UUID in its hyphenated form.
+pub fn random_uuid() -> String {
+    let mut bits: u128 = rand::random();
+    bits = (bits & !(0xf << 76)) | (0x4 << 76); // version 4
+    bits = (bits & !(0x3 << 62)) | (0x2 << 62); // RFC 4122 variant
+    let uuid = format!("{:032x}", bits);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &uuid[..8],
+        &uuid[8..12],
+        &uuid[12..16],
+        &uuid[16..20],
+        &uuid[20..]
+    )
+}
+
+/// Hex digits of the name hash naming each `--shard-depth` directory level.
+pub const SHARD_WIDTH: usize = 2;
+
+/// Moves the file of `path` down `depth` shard directories named after the
+/// SHA-256 of its lowercased name, so `photo.jpg` becomes `3f/a2/photo.jpg`
+/// at depth 2.
+///
+/// Names that differ only by case share their shard, so
+/// `--case-insensitive-conflict` still finds them side by side.
+pub fn shard(path: &Path, depth: usize) -> PathBuf {
+    let name = path.file_name().unwrap_or_default();
+    let digest = hex::encode(Sha256::digest(
+        name.to_string_lossy().to_lowercase().as_bytes(),
+    ));
+    let mut sharded = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    for level in 0..depth {
+        sharded.push(&digest[level * SHARD_WIDTH..(level + 1) * SHARD_WIDTH]);
+    }
+    sharded.push(name);
+    sharded
+}
+
+/// Whether `name` could be a shard directory.
+pub fn is_shard(name: &str) -> bool {
+    name.len() == SHARD_WIDTH && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Renders `template` into a path relative to the save directory.
+///
+/// Until the content is hashed, `{sha256}` renders as `sha256`. An empty
+/// `{ext}` takes the dot before it along, so `{sha256}.{ext}` leaves no
+/// trailing dot.
+pub fn render_name(template: &NameTemplate, meta: &NameMeta) -> PathBuf {
+    let name = Path::new(&meta.filename);
+    let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = name.extension().unwrap_or_default().to_string_lossy();
+    let mut rendered = String::new();
+    for part in &template.parts {
+        match part {
+            Part::Literal(text) => rendered.push_str(text),
+            Part::Placeholder(Placeholder::Date) => rendered.push_str(&meta.date),
+            Part::Placeholder(Placeholder::Uuid) => rendered.push_str(&meta.uuid),
+            Part::Placeholder(Placeholder::Orig) => rendered.push_str(&meta.filename),
+            Part::Placeholder(Placeholder::Stem) => rendered.push_str(&stem),
+            Part::Placeholder(Placeholder::Ext) if ext.is_empty() => {
+                if rendered.ends_with('.') {
+                    rendered.pop();
+                }
+            }
+            Part::Placeholder(Placeholder::Ext) => rendered.push_str(&ext),
+            Part::Placeholder(Placeholder::Sha256) => {
+                rendered.push_str(meta.sha256.as_deref().unwrap_or("sha256"))
+            }
+        }
+    }
+    // Placeholders are single sanitized components, but an empty one can
+    // still leave a leading or doubled `/`.
+    let path = Path::new(&rendered)
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect::<PathBuf>();
+    if path.as_os_str().is_empty() {
+        return PathBuf::from(&meta.filename);
+    }
+    path
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) civil date.
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
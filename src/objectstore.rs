@@ -0,0 +1,31 @@
+use anyhow::Result;
+use std::{future::Future, path::Path, pin::Pin};
+
+pub type ObjectFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// Storage that stored files are written through to, with `--s3-bucket`.
+///
+/// Files reach it only once their upload was accepted, after every check on
+/// the request; should one put fail, the request's other objects are deleted
+/// again, so a refused or broken upload does not stay there.
+pub trait ObjectStore: Send + Sync {
+    /// Uploads the file at `path` as the object `key`, returning its URL.
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        path: &'a Path,
+        content_type: &'a str,
+    ) -> ObjectFuture<'a, String>;
+
+    /// Removes the object `key`, which need not exist.
+    fn delete<'a>(&'a self, key: &'a str) -> ObjectFuture<'a, ()>;
+}
+
+/// The object key of a file stored at `relative` below the save directory.
+pub fn key_for(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
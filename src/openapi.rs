@@ -0,0 +1,302 @@
+use axum::Json;
+use serde_json::{Value, json};
+
+/// Serves the OpenAPI 3 description of the HTTP API.
+///
+/// The spec is written by hand; keep it in step with the routes in `lib` and
+/// the codes in [`crate::error::ApiError`].
+pub async fn openapi() -> Json<Value> {
+    Json(spec())
+}
+
+fn spec() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "petguard",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/upload": {
+                "post": {
+                    "summary": "Store the file parts of a multipart body",
+                    "description": "Clients sending Expect: 100-continue get their final status instead of 100 Continue when the upload is refused before its body is read: for credentials, a grant, draining, free inodes, If-None-Match, or a Content-Length over --max-request-size. 100 Continue is only sent once the body is read.",
+                    "parameters": [
+                        {
+                            "name": "If-None-Match",
+                            "in": "header",
+                            "description": "SHA-256 of content the client is about to send; with --dedup, answer 304 without reading the body if it is already stored",
+                            "schema": {"type": "string"},
+                        },
+                        {"$ref": "#/components/parameters/GrantName"},
+                        {"$ref": "#/components/parameters/GrantExpires"},
+                        {"$ref": "#/components/parameters/GrantMaxSize"},
+                        {"$ref": "#/components/parameters/GrantSignature"},
+                    ],
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "multipart/form-data": {
+                                "schema": {
+                                    "type": "object",
+                                    "additionalProperties": {"type": "string", "format": "binary"},
+                                },
+                            },
+                        },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "All file parts were stored",
+                            "content": {"application/json": {"schema": {"$ref": "#/components/schemas/UploadResponse"}}},
+                        },
+                        "304": {"description": "Content matching If-None-Match is already stored"},
+                        "400": {"$ref": "#/components/responses/Error"},
+                        "401": {"$ref": "#/components/responses/Error"},
+                        "403": {"$ref": "#/components/responses/Error"},
+                        "413": {"$ref": "#/components/responses/Error"},
+                        "415": {"$ref": "#/components/responses/Error"},
+                        "417": {"$ref": "#/components/responses/Error"},
+                        "422": {"$ref": "#/components/responses/Error"},
+                        "500": {"$ref": "#/components/responses/Error"},
+                        "503": {"$ref": "#/components/responses/Error"},
+                        "507": {"$ref": "#/components/responses/Error"},
+                    },
+                },
+            },
+            "/verify": {
+                "post": {
+                    "summary": "Compare a stored file with an expected SHA-256 digest",
+                    "requestBody": {
+                        "required": true,
+                        "content": {"application/json": {"schema": {
+                            "type": "object",
+                            "required": ["name", "sha256"],
+                            "properties": {
+                                "name": {"type": "string", "description": "Stored filename; with --name-template, the stored path relative to the save directory"},
+                                "sha256": {"type": "string"},
+                            },
+                        }}},
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "Digest of the stored file",
+                            "content": {"application/json": {"schema": {
+                                "type": "object",
+                                "properties": {
+                                    "name": {"type": "string"},
+                                    "sha256": {"type": "string"},
+                                    "match": {"type": "boolean"},
+                                },
+                            }}},
+                        },
+                        "400": {"$ref": "#/components/responses/Error"},
+                        "404": {"$ref": "#/components/responses/Error"},
+                        "500": {"$ref": "#/components/responses/Error"},
+                    },
+                },
+            },
+            "/ws-upload": {
+                "get": {
+                    "summary": "Upload one file over a WebSocket",
+                    "description": "Send a JSON text message {filename, size?, content_type?}, then the file as binary messages. The saved file is returned as a JSON text message before the server closes. A refused upload is closed with the error code as the reason: 1008 for a refused file, 1002 for messages out of order and 1011 when storing failed.",
+                    "parameters": [
+                        {"$ref": "#/components/parameters/GrantName"},
+                        {"$ref": "#/components/parameters/GrantExpires"},
+                        {"$ref": "#/components/parameters/GrantMaxSize"},
+                        {"$ref": "#/components/parameters/GrantSignature"},
+                    ],
+                    "responses": {
+                        "101": {"description": "Switching to the WebSocket protocol"},
+                        "401": {"$ref": "#/components/responses/Error"},
+                        "403": {"$ref": "#/components/responses/Error"},
+                        "503": {"$ref": "#/components/responses/Error"},
+                    },
+                },
+            },
+            "/livez": {
+                "get": {
+                    "summary": "Liveness probe",
+                    "responses": {"200": {"description": "The process is running"}},
+                },
+            },
+            "/readyz": {
+                "get": {
+                    "summary": "Readiness probe",
+                    "responses": {
+                        "200": {"description": "Ready to accept uploads"},
+                        "503": {"description": "Draining for shutdown, or storage is not writable"},
+                    },
+                },
+            },
+            "/metrics": {
+                "get": {
+                    "summary": "Request counts and latencies by method, route and status class",
+                    "description": "Only served with --metrics.",
+                    "responses": {"200": {"description": "Prometheus text format", "content": {"text/plain": {}}}},
+                },
+            },
+            "/admin/config": {
+                "get": {
+                    "summary": "Effective configuration, with secrets redacted",
+                    "description": "Only served with --enable-admin.",
+                    "security": [{"bearer": []}],
+                    "responses": {
+                        "200": {"description": "Configuration", "content": {"application/json": {"schema": {"type": "object"}}}},
+                        "401": {"$ref": "#/components/responses/Error"},
+                    },
+                },
+            },
+            "/admin/logs": {
+                "get": {
+                    "summary": "Stream log events as server-sent events",
+                    "description": "Only served with --enable-admin. Sends the last 256 events, then new ones as JSON {time_ms, level, target, fields}; an event named lagged carries the number of events skipped for a slow reader.",
+                    "security": [{"bearer": []}],
+                    "responses": {
+                        "200": {"description": "Event stream", "content": {"text/event-stream": {}}},
+                        "401": {"$ref": "#/components/responses/Error"},
+                    },
+                },
+            },
+            "/admin/upload-token": {
+                "post": {
+                    "summary": "Mint an upload grant for one named file",
+                    "description": "Only served with --enable-admin and --upload-secret.",
+                    "security": [{"bearer": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": {"application/json": {"schema": {
+                            "type": "object",
+                            "required": ["name", "max_size"],
+                            "properties": {
+                                "name": {"type": "string"},
+                                "max_size": {"type": "integer"},
+                                "expires_in": {"type": "integer", "description": "Seconds, 3600 by default and at most 604800"},
+                            },
+                        }}},
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "Query parameters authorizing the upload",
+                            "content": {"application/json": {"schema": {
+                                "type": "object",
+                                "properties": {
+                                    "name": {"type": "string"},
+                                    "expires": {"type": "integer"},
+                                    "max_size": {"type": "integer"},
+                                    "signature": {"type": "string"},
+                                },
+                            }}},
+                        },
+                        "401": {"$ref": "#/components/responses/Error"},
+                    },
+                },
+            },
+            "/admin/shutdown": {
+                "post": {
+                    "summary": "Start a graceful shutdown",
+                    "description": "Only served with --enable-admin.",
+                    "security": [{"bearer": []}],
+                    "responses": {
+                        "202": {"description": "Shutdown started"},
+                        "401": {"$ref": "#/components/responses/Error"},
+                    },
+                },
+            },
+        },
+        "components": {
+            "securitySchemes": {
+                "bearer": {"type": "http", "scheme": "bearer"},
+            },
+            "parameters": {
+                "GrantName": {"name": "name", "in": "query", "description": "With --upload-secret: the name the file is stored under", "schema": {"type": "string"}},
+                "GrantExpires": {"name": "expires", "in": "query", "description": "With --upload-secret: Unix time the grant expires", "schema": {"type": "integer"}},
+                "GrantMaxSize": {"name": "max_size", "in": "query", "description": "With --upload-secret: largest file the grant allows", "schema": {"type": "integer"}},
+                "GrantSignature": {"name": "signature", "in": "query", "description": "With --upload-secret: hex HMAC-SHA256 of name, expires and max_size joined by newlines", "schema": {"type": "string"}},
+            },
+            "responses": {
+                "Error": {
+                    "description": "The request failed",
+                    "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Error"}}},
+                },
+            },
+            "schemas": {
+                "Error": {
+                    "type": "object",
+                    "required": ["error", "message"],
+                    "properties": {
+                        "error": {"type": "string", "description": "Stable error code"},
+                        "message": {"type": "string"},
+                        "rejected": {
+                            "type": "array",
+                            "description": "With files_rejected, why each file was rejected",
+                            "items": {"$ref": "#/components/schemas/Rejection"},
+                        },
+                    },
+                },
+                "Rejection": {
+                    "type": "object",
+                    "required": ["filename", "error", "message"],
+                    "properties": {
+                        "filename": {"type": "string"},
+                        "error": {"type": "string", "description": "Stable error code"},
+                        "message": {"type": "string"},
+                    },
+                },
+                "SavedFile": {
+                    "type": "object",
+                    "required": ["path", "filename", "content_type", "size", "sha256", "deduplicated"],
+                    "properties": {
+                        "path": {"type": "string", "description": "Where the file is stored; without --save-dir, its object key in --s3-bucket"},
+                        "filename": {"type": "string"},
+                        "content_type": {"type": "string"},
+                        "size": {"type": "integer"},
+                        "declared_length": {"type": "integer"},
+                        "compressed_size": {"type": "integer"},
+                        "sha256": {"type": "string"},
+                        "deduplicated": {"type": "boolean"},
+                        "metadata_stripped": {"type": "boolean"},
+                        "phash": {"type": "string", "description": "64-bit perceptual hash as hex, with --phash"},
+                        "thumbnail": {"type": "string", "description": "Path of the JPEG thumbnail stored beside an image, with --thumbnail"},
+                        "file_type": {
+                            "type": "object",
+                            "description": "Type libmagic detected, with --use-libmagic",
+                            "properties": {
+                                "mime": {"type": "string"},
+                                "description": {"type": "string"},
+                            },
+                        },
+                        "object": {
+                            "type": "object",
+                            "description": "Object the file was put as, with --s3-bucket",
+                            "properties": {
+                                "key": {"type": "string"},
+                                "url": {"type": "string"},
+                            },
+                        },
+                    },
+                },
+                "UploadResponse": {
+                    "type": "object",
+                    "properties": {
+                        "saved_files": {"type": "array", "items": {"type": "string"}},
+                        "files": {"type": "array", "items": {"$ref": "#/components/schemas/SavedFile"}},
+                        "rejected": {
+                            "type": "array",
+                            "description": "Files rejected while others were saved, with --collect-rejections",
+                            "items": {"$ref": "#/components/schemas/Rejection"},
+                        },
+                        "incomplete": {
+                            "description": "With --partial-ok, why the body could not be read to its end; the files before that were saved",
+                            "$ref": "#/components/schemas/Error",
+                        },
+                        "fields": {
+                            "type": "object",
+                            "description": "Form fields without a filename, by name; a repeated name keeps its last value",
+                            "additionalProperties": {"type": "string"},
+                        },
+                    },
+                },
+            },
+        },
+    })
+}
@@ -0,0 +1,333 @@
+use axum::body::{Body, HttpBody};
+use bytes::Bytes;
+use http_body::{Frame, SizeHint};
+use memchr::memmem::Finder;
+use std::{
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Headers multer reads per part; a part with more fails to parse anyway.
+pub const MAX_PART_HEADERS: usize = 32;
+
+/// A part whose headers exceed `--max-part-headers` or `--max-part-header-size`.
+#[derive(Debug)]
+pub struct PartHeadersExceeded {
+    count: bool,
+}
+
+impl fmt::Display for PartHeadersExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.count {
+            write!(f, "a part has too many headers")
+        } else {
+            write!(f, "a part's headers are too large")
+        }
+    }
+}
+
+impl std::error::Error for PartHeadersExceeded {}
+
+/// The boundary of a `multipart/form-data` Content-Type, quoted or not.
+pub fn parse_boundary(content_type: &str) -> Option<&str> {
+    let mut params = content_type.split(';');
+    if !params
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+    let boundary = params.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim())
+    })?;
+    let boundary = boundary
+        .strip_prefix('"')
+        .and_then(|quoted| quoted.strip_suffix('"'))
+        .unwrap_or(boundary);
+    (!boundary.is_empty()).then_some(boundary)
+}
+
+#[derive(Clone, Copy)]
+pub struct PartLimits {
+    pub max_headers: usize,
+    pub max_bytes: usize,
+}
+
+enum Scan {
+    /// Looking for the next delimiter in part content.
+    Content,
+    /// After a delimiter. `lines` counts the lines ended so far, the rest of
+    /// the delimiter's own line first; `line` is the current one's length
+    /// without line breaks, and `bytes` counts the header block.
+    Headers {
+        lines: usize,
+        line: usize,
+        bytes: usize,
+    },
+    /// After the closing delimiter, or once a limit was hit.
+    Done,
+}
+
+/// A multipart body that fails as soon as a part's header block grows past
+/// the limits, before multer buffers it looking for its end.
+///
+/// Only the delimiters and header blocks are scanned byte by byte; part
+/// content is searched for the next delimiter a chunk at a time.
+pub struct LimitedParts {
+    inner: Body,
+    limits: PartLimits,
+    finder: Finder<'static>,
+    /// The end of the previous chunk, in case a delimiter straddles chunks.
+    tail: Vec<u8>,
+    scan: Scan,
+}
+
+impl LimitedParts {
+    pub fn new(inner: Body, boundary: &str, limits: PartLimits) -> Self {
+        let delimiter = format!("\r\n--{}", boundary).into_bytes();
+        Self {
+            inner,
+            limits,
+            finder: Finder::new(&delimiter).into_owned(),
+            // The first delimiter may open the body without a line break.
+            tail: b"\r\n".to_vec(),
+            scan: Scan::Content,
+        }
+    }
+
+    fn check(&mut self, mut data: &[u8]) -> Result<(), PartHeadersExceeded> {
+        while !data.is_empty() {
+            match &mut self.scan {
+                Scan::Content => match self.find_delimiter(data) {
+                    Some(end) => {
+                        data = &data[end..];
+                        self.tail.clear();
+                        self.scan = Scan::Headers {
+                            lines: 0,
+                            line: 0,
+                            bytes: 0,
+                        };
+                    }
+                    None => {
+                        self.keep_tail(data);
+                        return Ok(());
+                    }
+                },
+                Scan::Headers { lines, line, bytes } => {
+                    let byte = data[0];
+                    data = &data[1..];
+                    if *lines == 0 && *line == 0 && byte == b'-' {
+                        // `--` right after the delimiter closes the body.
+                        self.scan = Scan::Done;
+                        continue;
+                    }
+                    if *lines > 0 {
+                        *bytes += 1;
+                        if *bytes > self.limits.max_bytes {
+                            self.scan = Scan::Done;
+                            return Err(PartHeadersExceeded { count: false });
+                        }
+                    }
+                    match byte {
+                        // A blank line ends the headers.
+                        b'\n' if *lines > 0 && *line == 0 => self.scan = Scan::Content,
+                        b'\n' => {
+                            *lines += 1;
+                            *line = 0;
+                            if *lines - 1 > self.limits.max_headers {
+                                self.scan = Scan::Done;
+                                return Err(PartHeadersExceeded { count: true });
+                            }
+                        }
+                        b'\r' => {}
+                        _ => *line += 1,
+                    }
+                }
+                Scan::Done => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the first delimiter ends in `tail` followed by `data`, as an
+    /// offset into `data`.
+    fn find_delimiter(&self, data: &[u8]) -> Option<usize> {
+        let needle = self.finder.needle();
+        // The earliest match starts furthest back in the tail.
+        for split in (1..=self.tail.len().min(needle.len() - 1)).rev() {
+            if self.tail.ends_with(&needle[..split]) && data.starts_with(&needle[split..]) {
+                return Some(needle.len() - split);
+            }
+        }
+        self.finder.find(data).map(|start| start + needle.len())
+    }
+
+    fn keep_tail(&mut self, data: &[u8]) {
+        let keep = self.finder.needle().len() - 1;
+        if data.len() >= keep {
+            self.tail.clear();
+            self.tail.extend_from_slice(&data[data.len() - keep..]);
+        } else {
+            self.tail.extend_from_slice(data);
+            let excess = self.tail.len().saturating_sub(keep);
+            self.tail.drain(..excess);
+        }
+    }
+}
+
+impl HttpBody for LimitedParts {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame
+            && let Some(data) = frame.data_ref()
+            && let Err(e) = self.check(data)
+        {
+            return Poll::Ready(Some(Err(axum::Error::new(e))));
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: PartLimits = PartLimits {
+        max_headers: 4,
+        max_bytes: 200,
+    };
+
+    /// Checks `body` handed over in `size` byte reads, as a fresh upload.
+    fn check(body: &[u8], size: usize) -> Result<(), PartHeadersExceeded> {
+        let mut parts = LimitedParts::new(Body::empty(), "XYZ", LIMITS);
+        body.chunks(size).try_for_each(|chunk| parts.check(chunk))
+    }
+
+    /// A part with `headers` headers of `length` bytes each, lines included.
+    fn part(headers: usize, length: usize) -> Vec<u8> {
+        let mut part = b"--XYZ\r\n".to_vec();
+        for i in 0..headers {
+            let name = format!("X-H{}: ", i);
+            part.extend_from_slice(name.as_bytes());
+            part.resize(part.len() + length - name.len() - 2, b'a');
+            part.extend_from_slice(b"\r\n");
+        }
+        part.extend_from_slice(b"\r\ncontent\r\n");
+        part
+    }
+
+    fn body(parts: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = parts.concat();
+        body.extend_from_slice(b"--XYZ--\r\n");
+        body
+    }
+
+    #[test]
+    fn parts_within_limits() {
+        let body = body(&[part(4, 40), part(1, 10), part(2, 98)]);
+        for size in 1..=body.len() {
+            assert!(check(&body, size).is_ok(), "{} byte reads", size);
+        }
+    }
+
+    #[test]
+    fn too_many_headers() {
+        let body = body(&[part(1, 20), part(5, 20)]);
+        for size in 1..=body.len() {
+            let Err(err) = check(&body, size) else {
+                panic!("{} byte reads passed", size);
+            };
+            assert!(err.count, "{} byte reads", size);
+        }
+    }
+
+    #[test]
+    fn headers_too_large() {
+        let body = body(&[part(1, 20), part(3, 70)]);
+        for size in 1..=body.len() {
+            let Err(err) = check(&body, size) else {
+                panic!("{} byte reads passed", size);
+            };
+            assert!(!err.count, "{} byte reads", size);
+        }
+    }
+
+    #[test]
+    fn header_heavy_part_fails_before_its_end() {
+        // Thousands of tiny headers, and no blank line to end them.
+        let mut body = b"--XYZ\r\n".to_vec();
+        for _ in 0..5000 {
+            body.extend_from_slice(b"a:\r\n");
+        }
+        let mut parts = LimitedParts::new(Body::empty(), "XYZ", LIMITS);
+        let failed_at = body
+            .chunks(64)
+            .position(|chunk| parts.check(chunk).is_err());
+        assert_eq!(failed_at, Some(0));
+        // Once failed, the body is not scanned any further.
+        assert!(parts.check(b"\r\n--XYZ\r\n").is_ok());
+    }
+
+    #[test]
+    fn delimiter_split_across_reads() {
+        // Content with what could start a delimiter, then lines that would
+        // be too many headers were it taken for one.
+        let mut near_miss = b"--XYZ\r\nX-H: a\r\n\r\n\r\n--XY\r\n".to_vec();
+        near_miss.extend_from_slice(&b"a: b\r\n".repeat(10));
+        near_miss.extend_from_slice(b"\r\n-\r\n--\r\n");
+        let ok = body(&[near_miss.clone(), part(4, 20)]);
+        for size in 1..=ok.len() {
+            assert!(check(&ok, size).is_ok(), "{} byte reads", size);
+        }
+        // The real delimiter is found however the reads cut it.
+        let failing = body(&[near_miss, part(5, 20)]);
+        for size in 1..=failing.len() {
+            assert!(check(&failing, size).is_err(), "{} byte reads", size);
+        }
+    }
+
+    #[test]
+    fn closing_delimiter_ends_the_scan() {
+        let mut body = body(&[part(1, 20)]);
+        body.extend_from_slice(&part(10, 100));
+        for size in 1..=body.len() {
+            assert!(check(&body, size).is_ok(), "{} byte reads", size);
+        }
+    }
+
+    #[test]
+    fn boundaries() {
+        assert_eq!(
+            parse_boundary("multipart/form-data; boundary=XYZ"),
+            Some("XYZ")
+        );
+        assert_eq!(
+            parse_boundary("Multipart/Form-Data;charset=utf-8; BOUNDARY=\"a b=c\""),
+            Some("a b=c")
+        );
+        assert_eq!(parse_boundary("multipart/form-data"), None);
+        assert_eq!(parse_boundary("multipart/form-data; boundary="), None);
+        assert_eq!(parse_boundary("multipart/form-data; boundary=\"\""), None);
+        assert_eq!(parse_boundary("multipart/mixed; boundary=XYZ"), None);
+        assert_eq!(parse_boundary("text/plain; boundary=XYZ"), None);
+    }
+}
@@ -0,0 +1,19 @@
+use image_hasher::{HashAlg, HasherConfig};
+
+/// Whether `head`, the first bytes of a file, starts like an image we decode.
+pub fn is_image(head: &[u8]) -> bool {
+    image::guess_format(head).is_ok()
+}
+
+/// 64-bit DCT perceptual hash of an encoded image as hex, or `None` when it
+/// cannot be decoded.
+///
+/// Near-duplicate images have hashes a small Hamming distance apart.
+pub fn perceptual_hash(data: &[u8]) -> Option<String> {
+    let image = image::load_from_memory(data).ok()?;
+    let hasher = HasherConfig::new()
+        .hash_alg(HashAlg::Mean)
+        .preproc_dct()
+        .to_hasher();
+    Some(hex::encode(hasher.hash_image(&image).as_bytes()))
+}
@@ -0,0 +1,69 @@
+use crate::upgrade::LISTEN_FD_ENV;
+use anyhow::{Result, anyhow};
+use nix::{
+    errno::Errno,
+    sys::signal::kill,
+    unistd::{Pid, getpid, getppid},
+};
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The `--pid-file`, naming this process until it is dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes this process's PID to `path`.
+    ///
+    /// A file naming another running process is refused, or with `takeover`
+    /// warned about and overwritten. The predecessor of a successor started
+    /// on SIGUSR2 is still running, so the successor always takes over.
+    pub fn create(path: &Path, takeover: bool) -> Result<Self> {
+        let predecessor = env::var_os(LISTEN_FD_ENV).map(|_| getppid().as_raw());
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                if let Ok(pid) = contents.trim().parse::<i32>()
+                    && pid > 0
+                    && pid != getpid().as_raw()
+                    && Some(pid) != predecessor
+                    && running(pid)
+                {
+                    if !takeover {
+                        return Err(anyhow!(
+                            "{:?} names running process {}; is petguard already running?",
+                            path,
+                            pid
+                        ));
+                    }
+                    tracing::warn!("{:?} names running process {}, taking it over", path, pid);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(anyhow!("Failed to read {:?}: {}", path, e)),
+        }
+        fs::write(path, format!("{}\n", getpid()))
+            .map_err(|e| anyhow!("Failed to write {:?}: {}", path, e))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    /// Removes the file, unless a successor has written its own PID to it.
+    fn drop(&mut self) {
+        let ours = fs::read_to_string(&self.path)
+            .is_ok_and(|contents| contents.trim() == getpid().to_string());
+        if ours && let Err(e) = fs::remove_file(&self.path) {
+            tracing::warn!("Failed to remove {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Whether a process with this PID exists, even if we may not signal it.
+fn running(pid: i32) -> bool {
+    matches!(kill(Pid::from_raw(pid), None), Ok(()) | Err(Errno::EPERM))
+}
@@ -0,0 +1,102 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::{self, MissedTickBehavior};
+
+/// Distinct messages tracked at once; past this, new ones are logged as usual.
+const MAX_TRACKED: usize = 1024;
+
+#[derive(Clone, Copy)]
+enum Level {
+    Warn,
+    Error,
+}
+
+/// Logs failures of the upload path, collapsing repeats of the same message
+/// with `--log-repeat-interval`.
+///
+/// The first occurrence is logged at once; further ones are only counted,
+/// and [`summarize`] logs the count every interval while they keep coming.
+/// An interval without repeats forgets the message, so its next occurrence
+/// is logged at once again.
+pub struct RepeatLog {
+    interval: Option<Duration>,
+    repeats: Mutex<HashMap<String, (Level, u64)>>,
+}
+
+impl RepeatLog {
+    pub fn new(interval: Option<Duration>) -> Self {
+        Self {
+            interval,
+            repeats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn warn(&self, message: String) {
+        self.log(Level::Warn, message);
+    }
+
+    pub fn error(&self, message: String) {
+        self.log(Level::Error, message);
+    }
+
+    fn log(&self, level: Level, message: String) {
+        if self.interval.is_some() {
+            let mut repeats = self.repeats.lock().unwrap();
+            if let Some((_, count)) = repeats.get_mut(&message) {
+                *count += 1;
+                return;
+            }
+            if repeats.len() < MAX_TRACKED {
+                repeats.insert(message.clone(), (level, 0));
+            }
+        }
+        emit(level, &message);
+    }
+
+    /// Logs how often each message repeated since the last call, and forgets
+    /// those that did not.
+    fn flush(&self, interval: Duration) {
+        let mut repeats = self.repeats.lock().unwrap();
+        repeats.retain(|message, (level, count)| {
+            if *count == 0 {
+                return false;
+            }
+            emit(
+                *level,
+                &format!(
+                    "{} (repeated {}x in the last {}s)",
+                    message,
+                    count,
+                    interval.as_secs()
+                ),
+            );
+            *count = 0;
+            true
+        });
+    }
+}
+
+fn emit(level: Level, message: &str) {
+    match level {
+        Level::Warn => tracing::warn!("{}", message),
+        Level::Error => tracing::error!("{}", message),
+    }
+}
+
+/// Logs the repeat counts every `--log-repeat-interval`.
+pub async fn summarize(log: Arc<RepeatLog>) {
+    let Some(interval) = log.interval else {
+        return;
+    };
+    let mut ticks = time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes at once.
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        log.flush(interval);
+    }
+}
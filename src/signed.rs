@@ -0,0 +1,141 @@
+use crate::{AppState, error::ApiError};
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::Uri,
+};
+use hmac::{Hmac, KeyInit, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Lifetime of a grant minted by `/admin/upload-token` unless asked otherwise.
+const DEFAULT_EXPIRES_IN: u64 = 3600;
+
+/// Longest lifetime a grant is minted with; longer requests are shortened.
+const MAX_EXPIRES_IN: u64 = 7 * 24 * 3600;
+
+/// A pre-authorized upload, sent as the query parameters
+/// `?name=<file>&expires=<unix seconds>&max_size=<bytes>&signature=<hex>`.
+///
+/// The signature is the HMAC-SHA256, keyed with `--upload-secret`, of `name`,
+/// `expires` and `max_size` joined by newlines. The single file it allows is
+/// stored under `name`, whatever the client calls it.
+#[derive(Deserialize, Serialize)]
+pub struct UploadGrant {
+    pub name: String,
+    pub expires: u64,
+    pub max_size: u64,
+    pub signature: String,
+}
+
+impl UploadGrant {
+    fn new(secret: &[u8], name: String, expires: u64, max_size: u64) -> Self {
+        let mac = mac(secret, &name, expires, max_size);
+        Self {
+            signature: hex::encode(mac.finalize().into_bytes()),
+            name,
+            expires,
+            max_size,
+        }
+    }
+
+    /// The grant in `uri`'s query when uploads need one, with its signature
+    /// and expiry checked.
+    pub fn authorize(state: &AppState, uri: &Uri) -> Result<Option<Self>, ApiError> {
+        let Some(secret) = &state.upload_secret else {
+            return Ok(None);
+        };
+        let Ok(Query(grant)) = Query::<Self>::try_from_uri(uri) else {
+            return Err(ApiError::Unauthorized);
+        };
+        let signature = hex::decode(&grant.signature).map_err(|_| ApiError::InvalidToken)?;
+        mac(secret, &grant.name, grant.expires, grant.max_size)
+            .verify_slice(&signature)
+            .map_err(|_| ApiError::InvalidToken)?;
+        if grant.expires <= unix_now() {
+            return Err(ApiError::InvalidToken);
+        }
+        Ok(Some(grant))
+    }
+}
+
+fn mac(secret: &[u8], name: &str, expires: u64, max_size: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(format!("{}\n{}\n{}", name, expires, max_size).as_bytes());
+    mac
+}
+
+/// When a grant asked to last `expires_in` seconds from `now` expires.
+fn expiry(now: u64, expires_in: Option<u64>) -> u64 {
+    let expires_in = expires_in.unwrap_or(DEFAULT_EXPIRES_IN).min(MAX_EXPIRES_IN);
+    now.saturating_add(expires_in)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[derive(Deserialize)]
+pub struct GrantRequest {
+    name: String,
+    max_size: u64,
+    expires_in: Option<u64>,
+}
+
+/// Mints an upload grant for a file the caller names, for handing to a client.
+pub async fn upload_token(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<GrantRequest>,
+) -> Result<Json<UploadGrant>, ApiError> {
+    let Some(secret) = &state.upload_secret else {
+        return Err(ApiError::NotFound);
+    };
+    let expires = expiry(unix_now(), req.expires_in);
+    Ok(Json(UploadGrant::new(
+        secret,
+        req.name,
+        expires,
+        req.max_size,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry_defaults_and_clamps() {
+        assert_eq!(expiry(1000, None), 1000 + DEFAULT_EXPIRES_IN);
+        assert_eq!(expiry(1000, Some(60)), 1060);
+        assert_eq!(expiry(1000, Some(u64::MAX)), 1000 + MAX_EXPIRES_IN);
+        assert_eq!(expiry(u64::MAX - 1, Some(60)), u64::MAX);
+    }
+
+    #[test]
+    fn minted_grants_verify() {
+        let grant = UploadGrant::new(b"secret", "cat.jpg".to_string(), 1000, 50);
+        let signature = hex::decode(&grant.signature).unwrap();
+        assert!(
+            mac(b"secret", "cat.jpg", 1000, 50)
+                .verify_slice(&signature)
+                .is_ok()
+        );
+        assert!(
+            mac(b"secret", "cat.jpg", 1001, 50)
+                .verify_slice(&signature)
+                .is_err()
+        );
+        assert!(
+            mac(b"other", "cat.jpg", 1000, 50)
+                .verify_slice(&signature)
+                .is_err()
+        );
+    }
+}
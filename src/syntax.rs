@@ -0,0 +1,66 @@
+use serde::de::IgnoredAny;
+use std::{fmt, path::Path};
+use yaml_rust2::YamlLoader;
+
+/// A text format uploads are checked to parse as, with `--validate-json` and
+/// `--validate-yaml`.
+#[derive(Clone, Copy, Debug)]
+pub enum Syntax {
+    Json,
+    Yaml,
+}
+
+/// An upload that does not parse as the format of its extension.
+#[derive(Debug)]
+pub struct Malformed {
+    syntax: Syntax,
+    error: String,
+}
+
+impl fmt::Display for Malformed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.syntax {
+            Syntax::Json => "JSON",
+            Syntax::Yaml => "YAML",
+        };
+        write!(f, "file is not well-formed {}: {}", name, self.error)
+    }
+}
+
+impl std::error::Error for Malformed {}
+
+impl Syntax {
+    /// The format `filename` is checked against, if its extension is one that
+    /// is being validated.
+    pub fn of(filename: &str, json: bool, yaml: bool) -> Option<Self> {
+        let ext = Path::new(filename)
+            .extension()?
+            .to_str()?
+            .to_ascii_lowercase();
+        match ext.as_str() {
+            "json" if json => Some(Self::Json),
+            "yaml" | "yml" if yaml => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+
+    /// Parses `content` without keeping the result.
+    pub fn check(self, content: &[u8]) -> Result<(), Malformed> {
+        let error = match self {
+            Self::Json => serde_json::from_slice::<IgnoredAny>(content)
+                .err()
+                .map(|e| e.to_string()),
+            Self::Yaml => match std::str::from_utf8(content) {
+                Ok(text) => YamlLoader::load_from_str(text).err().map(|e| e.to_string()),
+                Err(e) => Some(e.to_string()),
+            },
+        };
+        match error {
+            Some(error) => Err(Malformed {
+                syntax: self,
+                error,
+            }),
+            None => Ok(()),
+        }
+    }
+}
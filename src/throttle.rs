@@ -0,0 +1,36 @@
+use std::time::{Duration, Instant};
+
+/// Token bucket holding up to one second worth of bytes.
+///
+/// Reservations may overdraw the bucket; the caller then sleeps for the
+/// returned duration, which is how long the debt takes to refill.
+pub struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a limiter allowing `rate` bytes per second.
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes `bytes` from the bucket, returning how long to wait before sending them.
+    pub fn reserve(&mut self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last = now;
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use crate::dimensions::Dimensions;
+use image::{DynamicImage, ImageFormat, ImageResult};
+use std::{
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+/// Where the thumbnail of a stored image goes: beside it, with its extension
+/// replaced by `.thumb.jpg`.
+pub fn path_for(filepath: &Path) -> PathBuf {
+    let stem = filepath.file_stem().unwrap_or_default().to_string_lossy();
+    filepath.with_file_name(format!("{}.thumb.jpg", stem))
+}
+
+/// Whether a file in the save directory is a thumbnail rather than an upload.
+pub fn is_thumbnail(name: &str) -> bool {
+    name.ends_with(".thumb.jpg")
+}
+
+/// Decodes an image and encodes it as a JPEG fitting within `size`, keeping
+/// its aspect ratio. Images already that small are only re-encoded.
+pub fn thumbnail(data: &[u8], size: Dimensions) -> ImageResult<Vec<u8>> {
+    let mut image = image::load_from_memory(data)?;
+    let (width, height) = (size.width as u32, size.height as u32);
+    if image.width() > width || image.height() > height {
+        image = image.thumbnail(width, height);
+    }
+    // JPEG has no alpha channel.
+    let image = DynamicImage::ImageRgb8(image.into_rgb8());
+    let mut encoded = Cursor::new(Vec::new());
+    image.write_to(&mut encoded, ImageFormat::Jpeg)?;
+    Ok(encoded.into_inner())
+}
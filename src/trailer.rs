@@ -0,0 +1,105 @@
+use axum::{
+    body::{Body, HttpBody},
+    extract::Request,
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use bytes::{Buf, Bytes};
+use http_body::{Frame, SizeHint};
+use std::{
+    future::poll_fn,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+/// Bytes still read after the multipart body ended while looking for trailers.
+const MAX_EPILOGUE: usize = 64 * 1024;
+
+/// Trailers of the request body, shared between the body and the handler.
+#[derive(Clone)]
+pub struct Trailers(Arc<Mutex<Shared>>);
+
+struct Shared {
+    body: Body,
+    trailers: Option<HeaderMap>,
+}
+
+impl Trailers {
+    /// Reads what is left of the body, which parsers stop short of once they
+    /// saw the closing boundary, then returns the named trailer.
+    pub async fn get(&self, name: &str) -> Option<String> {
+        let mut remaining = MAX_EPILOGUE;
+        poll_fn(|cx| {
+            let mut shared = self.0.lock().unwrap();
+            while shared.trailers.is_none() {
+                match shared.poll_frame(cx) {
+                    Poll::Ready(Some(Ok(frame))) => match frame.data_ref() {
+                        Some(data) if data.remaining() <= remaining => {
+                            remaining -= data.remaining();
+                        }
+                        Some(_) => break,
+                        None => {}
+                    },
+                    Poll::Ready(_) => break,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            Poll::Ready(())
+        })
+        .await;
+        let shared = self.0.lock().unwrap();
+        let value = shared.trailers.as_ref()?.get(name)?;
+        value.to_str().ok().map(str::to_string)
+    }
+}
+
+impl Shared {
+    fn poll_frame(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let frame = Pin::new(&mut self.body).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame
+            && let Some(trailers) = frame.trailers_ref()
+        {
+            self.trailers = Some(trailers.clone());
+        }
+        frame
+    }
+}
+
+/// Routes the request body through a [`Trailers`] extension so the handler
+/// can read its trailers.
+pub async fn capture_trailers(mut request: Request, next: Next) -> Response {
+    let trailers = Trailers(Arc::new(Mutex::new(Shared {
+        body: std::mem::take(request.body_mut()),
+        trailers: None,
+    })));
+    *request.body_mut() = Body::new(TrailerBody(trailers.clone()));
+    request.extensions_mut().insert(trailers);
+    next.run(request).await
+}
+
+struct TrailerBody(Trailers);
+
+impl HttpBody for TrailerBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        self.0.0.lock().unwrap().poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.0.0.lock().unwrap().body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.0.0.lock().unwrap().body.size_hint()
+    }
+}
@@ -0,0 +1,94 @@
+use crate::AppState;
+use anyhow::{Result, anyhow};
+use nix::{
+    fcntl::{FcntlArg, FdFlag, fcntl},
+    unistd::dup,
+};
+use std::{
+    env,
+    net::TcpListener,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    process::Command,
+    signal::unix::{SignalKind, signal},
+};
+
+/// Environment variable telling a successor which fd is the inherited listener.
+pub const LISTEN_FD_ENV: &str = "PETGUARD_LISTEN_FD";
+
+/// How long a successor has to fail its startup before the old process hands over.
+const STARTUP_GRACE: Duration = Duration::from_secs(2);
+
+/// The listening socket handed down by the process that started this one on SIGUSR2.
+pub fn inherited_listener() -> Result<Option<TcpListener>> {
+    let Ok(fd) = env::var(LISTEN_FD_ENV) else {
+        return Ok(None);
+    };
+    let fd: RawFd = fd
+        .parse()
+        .map_err(|_| anyhow!("invalid {} `{}`", LISTEN_FD_ENV, fd))?;
+    // SAFETY: the predecessor passed this fd for its listener, which nothing
+    // else in this process owns.
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    // Not passed on to --on-upload-cmd or a later successor by accident.
+    fcntl(&listener, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    listener.set_nonblocking(true)?;
+    listener
+        .local_addr()
+        .map_err(|e| anyhow!("{} {} is not a listening socket: {}", LISTEN_FD_ENV, fd, e))?;
+    Ok(Some(listener))
+}
+
+/// On SIGUSR2, starts a new copy of the server sharing `listener`, then shuts
+/// this one down so it drains its uploads while the copy accepts connections.
+///
+/// The copy is run from `argv[0]` with the same arguments, so a binary
+/// replaced on disk is picked up. If it exits during startup this process
+/// keeps serving.
+pub async fn upgrade_on_signal(listener: OwnedFd, state: Arc<AppState>) {
+    let mut usr2 = match signal(SignalKind::user_defined2()) {
+        Ok(usr2) => usr2,
+        Err(e) => {
+            tracing::error!("Failed to install SIGUSR2 handler: {}", e);
+            return;
+        }
+    };
+    while usr2.recv().await.is_some() {
+        match start_successor(&listener).await {
+            Ok(pid) => {
+                println!("started successor {}", pid);
+                state.shutdown.notify_one();
+                return;
+            }
+            Err(e) => tracing::error!("Upgrade failed, still serving: {:#}", e),
+        }
+    }
+}
+
+async fn start_successor(listener: &OwnedFd) -> Result<u32> {
+    let mut args: Vec<_> = env::args_os().collect();
+    if args.is_empty() {
+        return Err(anyhow!("no argv[0] to restart"));
+    }
+    let program = args.remove(0);
+    // The duplicate is not close-on-exec, so the successor inherits it.
+    let inherited = dup(listener)?;
+    let spawned = Command::new(&program)
+        .args(args)
+        .env(LISTEN_FD_ENV, inherited.as_raw_fd().to_string())
+        .spawn();
+    drop(inherited);
+    let mut child = spawned.map_err(|e| anyhow!("Failed to execute {:?}: {}", program, e))?;
+    let pid = child.id().unwrap_or_default();
+    if let Ok(status) = tokio::time::timeout(STARTUP_GRACE, child.wait()).await {
+        return Err(anyhow!(
+            "successor {} exited during startup: {}",
+            pid,
+            status?
+        ));
+    }
+    Ok(pid)
+}
@@ -0,0 +1,452 @@
+use crate::{AppState, dimensions::Dimensions, error::ApiError};
+use anyhow::{Result, anyhow};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tokio::signal;
+
+/// What is known about an upload before its content arrives.
+pub struct FieldMeta<'a> {
+    pub filename: &'a str,
+    pub declared_length: Option<u64>,
+}
+
+/// Why [`ValidationPolicy::validate`] refused an upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    ForbiddenName,
+    ExtensionNotAllowed,
+    TooLarge,
+    ContentMismatch,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::ForbiddenName => "filename is forbidden",
+            Self::ExtensionNotAllowed => "file extension is not allowed",
+            Self::TooLarge => "file exceeds the size limit",
+            Self::ContentMismatch => "file content does not match its extension",
+        })
+    }
+}
+
+impl std::error::Error for RejectReason {}
+
+impl From<RejectReason> for ApiError {
+    fn from(reason: RejectReason) -> Self {
+        match reason {
+            RejectReason::ForbiddenName => Self::ForbiddenName,
+            RejectReason::ExtensionNotAllowed => Self::ExtensionNotAllowed,
+            RejectReason::TooLarge => Self::FileTooLarge,
+            RejectReason::ContentMismatch => Self::ContentMismatch,
+        }
+    }
+}
+
+/// Leading bytes a file with an extension listed in `sniff_extensions` must
+/// start with, one of them if several are given.
+const SIGNATURES: &[(&[&str], &[&[u8]])] = &[
+    (&["exe", "dll", "scr", "sys"], &[b"MZ"]),
+    (&["elf", "so"], &[b"\x7fELF"]),
+    (
+        &["msi", "doc", "xls", "ppt"],
+        &[b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1"],
+    ),
+    (&["sh", "bash"], &[b"#!"]),
+    (&["php", "phtml"], &[b"<?php", b"<?="]),
+    (
+        &["zip", "jar", "apk", "docx", "xlsx"],
+        &[b"PK\x03\x04", b"PK\x05\x06"],
+    ),
+    (&["pdf"], &[b"%PDF-"]),
+    (&["jpg", "jpeg"], &[b"\xff\xd8\xff"]),
+    (&["png"], &[b"\x89PNG\r\n\x1a\n"]),
+    (&["gif"], &[b"GIF87a", b"GIF89a"]),
+];
+
+fn signatures(extension: &str) -> Option<&'static [&'static [u8]]> {
+    SIGNATURES
+        .iter()
+        .find(|(extensions, _)| extensions.contains(&extension))
+        .map(|(_, signatures)| *signatures)
+}
+
+/// Checks the start of an upload against the signatures of its extension.
+pub struct MagicProbe {
+    signatures: &'static [&'static [u8]],
+    head: Vec<u8>,
+    matched: bool,
+}
+
+impl MagicProbe {
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<(), RejectReason> {
+        if self.matched {
+            return Ok(());
+        }
+        let longest = self.signatures.iter().map(|s| s.len()).max().unwrap_or(0);
+        let wanted = longest.saturating_sub(self.head.len()).min(chunk.len());
+        self.head.extend_from_slice(&chunk[..wanted]);
+        if self.signatures.iter().any(|s| self.head.starts_with(s)) {
+            self.matched = true;
+        } else if self.head.len() >= longest {
+            return Err(RejectReason::ContentMismatch);
+        }
+        Ok(())
+    }
+
+    /// Rejects an upload that ended before a signature was seen.
+    pub fn finish(&self) -> Result<(), RejectReason> {
+        if self.matched {
+            Ok(())
+        } else {
+            Err(RejectReason::ContentMismatch)
+        }
+    }
+}
+
+/// An upload that grew past the policy's `max_size` while it was written.
+#[derive(Debug)]
+pub struct SizeExceeded {
+    pub max_size: u64,
+}
+
+impl fmt::Display for SizeExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "file is larger than {} bytes", self.max_size)
+    }
+}
+
+impl std::error::Error for SizeExceeded {}
+
+/// The rules every upload is held to.
+///
+/// Built from `--deny-name` and `--max-dimensions`, with the `[validation]`
+/// section of `--validation-file` overriding the rules it sets:
+///
+/// ```toml
+/// [validation]
+/// max_size = 10485760
+/// allowed_extensions = ["jpg", "png"]
+/// forbidden_names = ["*.exe"]
+/// max_dimensions = "4096x4096"
+/// sniff_extensions = ["exe", "sh", "php"]
+/// ```
+///
+/// Files with a `sniff_extensions` extension have to start with the magic
+/// bytes of that type, whether or not the extension is allowed; other
+/// uploads are taken at their name.
+pub struct ValidationPolicy {
+    pub max_size: Option<u64>,
+    pub max_dimensions: Option<Dimensions>,
+    allowed_extensions: Option<HashSet<String>>,
+    forbidden_names: GlobSet,
+    sniff_extensions: HashSet<String>,
+}
+
+impl ValidationPolicy {
+    /// Checks an upload's name and declared length; `max_size` and
+    /// `max_dimensions` are enforced again as the content is written.
+    pub fn validate(&self, field: &FieldMeta) -> Result<(), RejectReason> {
+        if self.forbidden_names.is_match(field.filename) {
+            return Err(RejectReason::ForbiddenName);
+        }
+        if let Some(allowed) = &self.allowed_extensions {
+            let extension = Path::new(field.filename)
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase());
+            if !extension.is_some_and(|ext| allowed.contains(&ext)) {
+                return Err(RejectReason::ExtensionNotAllowed);
+            }
+        }
+        if let (Some(max_size), Some(length)) = (self.max_size, field.declared_length)
+            && length > max_size
+        {
+            return Err(RejectReason::TooLarge);
+        }
+        Ok(())
+    }
+
+    /// A probe checking the content of `filename` if its extension is sniffed.
+    pub fn magic_probe(&self, filename: &str) -> Option<MagicProbe> {
+        let extension = Path::new(filename)
+            .extension()?
+            .to_string_lossy()
+            .to_lowercase();
+        if !self.sniff_extensions.contains(&extension) {
+            return None;
+        }
+        Some(MagicProbe {
+            signatures: signatures(&extension)?,
+            head: Vec::new(),
+            matched: false,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct ValidationFile {
+    #[serde(default)]
+    validation: Rules,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Rules {
+    max_size: Option<u64>,
+    allowed_extensions: Option<Vec<String>>,
+    forbidden_names: Option<Vec<String>>,
+    max_dimensions: Option<String>,
+    sniff_extensions: Option<Vec<String>>,
+}
+
+/// The flags and file a [`ValidationPolicy`] is built from, kept to rebuild it on SIGHUP.
+pub struct PolicySource {
+    pub forbidden_names: Vec<String>,
+    pub ignore_case: bool,
+    pub max_dimensions: Option<Dimensions>,
+    pub sniff_extensions: Vec<String>,
+    pub file: Option<PathBuf>,
+}
+
+impl PolicySource {
+    pub fn load(&self) -> Result<ValidationPolicy> {
+        let rules = match &self.file {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| anyhow!("Failed to read {:?}: {}", path, e))?;
+                toml::from_str::<ValidationFile>(&text)
+                    .map_err(|e| anyhow!("invalid validation file {:?}: {}", path, e))?
+                    .validation
+            }
+            None => Rules::default(),
+        };
+        let forbidden_names = rules
+            .forbidden_names
+            .as_ref()
+            .unwrap_or(&self.forbidden_names);
+        let max_dimensions = match rules.max_dimensions {
+            Some(dimensions) => Some(dimensions.parse().map_err(|e: String| anyhow!(e))?),
+            None => self.max_dimensions,
+        };
+        let allowed_extensions = rules.allowed_extensions.map(|extensions| {
+            extensions
+                .iter()
+                .map(|ext| ext.trim_start_matches('.').to_lowercase())
+                .collect()
+        });
+        let mut sniff_extensions = HashSet::new();
+        for ext in rules
+            .sniff_extensions
+            .as_ref()
+            .unwrap_or(&self.sniff_extensions)
+        {
+            let ext = ext.trim().trim_start_matches('.').to_lowercase();
+            if signatures(&ext).is_none() {
+                return Err(anyhow!("no magic bytes are known for `.{}` files", ext));
+            }
+            sniff_extensions.insert(ext);
+        }
+        Ok(ValidationPolicy {
+            max_size: rules.max_size,
+            max_dimensions,
+            allowed_extensions,
+            forbidden_names: build_glob_set(forbidden_names, self.ignore_case)?,
+            sniff_extensions,
+        })
+    }
+
+    /// Replaces `current` by a freshly loaded policy, keeping it if the file
+    /// does not load.
+    fn reload(&self, current: &RwLock<Arc<ValidationPolicy>>) {
+        match self.load() {
+            Ok(policy) => {
+                println!("reloaded validation policy");
+                *current.write().unwrap() = Arc::new(policy);
+            }
+            Err(e) => tracing::error!("{}", e),
+        }
+    }
+}
+
+fn build_glob_set(patterns: &[String], ignore_case: bool) -> Result<GlobSet> {
+    let mut set = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .case_insensitive(ignore_case)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| anyhow!("invalid forbidden name `{}`: {}", pattern, e))?;
+        set.add(glob);
+    }
+    Ok(set.build()?)
+}
+
+/// Rebuilds the policy whenever the process receives SIGHUP, keeping the
+/// current one if the file does not load.
+pub async fn reload_policy_on_hangup(state: Arc<AppState>, source: PolicySource) {
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())
+        .expect("failed to install signal handler");
+    while hangup.recv().await.is_some() {
+        source.reload(&state.validation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(file: Option<PathBuf>) -> PolicySource {
+        PolicySource {
+            forbidden_names: vec!["*.exe".to_string(), ".htaccess".to_string()],
+            ignore_case: false,
+            max_dimensions: None,
+            sniff_extensions: Vec::new(),
+            file,
+        }
+    }
+
+    /// A source reading `rules` as the `[validation]` section of a file.
+    fn with_file(rules: &str) -> (PolicySource, tempfile::NamedTempFile) {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), format!("[validation]\n{}", rules)).unwrap();
+        (flags(Some(file.path().to_path_buf())), file)
+    }
+
+    fn check(policy: &ValidationPolicy, filename: &str) -> Result<(), RejectReason> {
+        policy.validate(&FieldMeta {
+            filename,
+            declared_length: None,
+        })
+    }
+
+    #[test]
+    fn forbidden_names() {
+        let policy = flags(None).load().unwrap();
+        assert_eq!(
+            check(&policy, "setup.exe"),
+            Err(RejectReason::ForbiddenName)
+        );
+        assert_eq!(
+            check(&policy, ".htaccess"),
+            Err(RejectReason::ForbiddenName)
+        );
+        assert_eq!(check(&policy, "setup.exe.txt"), Ok(()));
+        // Case counts unless ignored.
+        assert_eq!(check(&policy, "SETUP.EXE"), Ok(()));
+        let mut ignoring = flags(None);
+        ignoring.ignore_case = true;
+        let policy = ignoring.load().unwrap();
+        assert_eq!(
+            check(&policy, "SETUP.EXE"),
+            Err(RejectReason::ForbiddenName)
+        );
+    }
+
+    #[test]
+    fn allowed_extensions() {
+        let (source, _file) = with_file("allowed_extensions = [\"jpg\", \".PNG\"]");
+        let policy = source.load().unwrap();
+        assert_eq!(check(&policy, "cat.jpg"), Ok(()));
+        assert_eq!(check(&policy, "CAT.JPG"), Ok(()));
+        assert_eq!(check(&policy, "cat.png"), Ok(()));
+        for name in ["cat.gif", "cat", "jpg", "cat.jpg.gif"] {
+            assert_eq!(
+                check(&policy, name),
+                Err(RejectReason::ExtensionNotAllowed),
+                "{}",
+                name
+            );
+        }
+        // Forbidden names still apply to allowed extensions.
+        let (source, _file) = with_file("allowed_extensions = [\"exe\"]");
+        let policy = source.load().unwrap();
+        assert_eq!(
+            check(&policy, "setup.exe"),
+            Err(RejectReason::ForbiddenName)
+        );
+        // Without the setting every extension is allowed.
+        assert_eq!(check(&flags(None).load().unwrap(), "cat.gif"), Ok(()));
+    }
+
+    #[test]
+    fn declared_length() {
+        let (source, _file) = with_file("max_size = 100");
+        let policy = source.load().unwrap();
+        let length = |declared_length| {
+            policy.validate(&FieldMeta {
+                filename: "cat.jpg",
+                declared_length,
+            })
+        };
+        assert_eq!(length(Some(99)), Ok(()));
+        assert_eq!(length(Some(100)), Ok(()));
+        assert_eq!(length(Some(101)), Err(RejectReason::TooLarge));
+        // Without a declared length the size is only checked while writing.
+        assert_eq!(length(None), Ok(()));
+        assert_eq!(policy.max_size, Some(100));
+    }
+
+    #[test]
+    fn file_overrides_flags() {
+        let (source, _file) = with_file("forbidden_names = [\"*.sh\"]");
+        let policy = source.load().unwrap();
+        assert_eq!(check(&policy, "setup.exe"), Ok(()));
+        assert_eq!(check(&policy, "run.sh"), Err(RejectReason::ForbiddenName));
+        let (source, _file) = with_file("unknown = 1");
+        assert!(source.load().is_err());
+        let (source, _file) = with_file("sniff_extensions = [\"txt\"]");
+        assert!(source.load().is_err());
+    }
+
+    #[test]
+    fn sniffed_content() {
+        let (source, _file) = with_file("sniff_extensions = [\"png\"]");
+        let policy = source.load().unwrap();
+        assert!(policy.magic_probe("cat.jpg").is_none());
+        let mut probe = policy.magic_probe("cat.PNG").unwrap();
+        probe.feed(b"\x89PN").unwrap();
+        probe.feed(b"G\r\n\x1a\nrest").unwrap();
+        assert_eq!(probe.finish(), Ok(()));
+        let mut probe = policy.magic_probe("cat.png").unwrap();
+        assert_eq!(
+            probe.feed(b"GIF89a rest"),
+            Err(RejectReason::ContentMismatch)
+        );
+        let mut probe = policy.magic_probe("cat.png").unwrap();
+        probe.feed(b"\x89").unwrap();
+        assert_eq!(probe.finish(), Err(RejectReason::ContentMismatch));
+    }
+
+    #[test]
+    fn reload_replaces_the_policy() {
+        let (source, file) = with_file("allowed_extensions = [\"jpg\"]");
+        let current = RwLock::new(Arc::new(source.load().unwrap()));
+        let policy = || current.read().unwrap().clone();
+        assert_eq!(
+            check(&policy(), "cat.png"),
+            Err(RejectReason::ExtensionNotAllowed)
+        );
+        std::fs::write(
+            file.path(),
+            "[validation]\nallowed_extensions = [\"png\"]\n",
+        )
+        .unwrap();
+        source.reload(&current);
+        assert_eq!(check(&policy(), "cat.png"), Ok(()));
+        assert_eq!(
+            check(&policy(), "cat.jpg"),
+            Err(RejectReason::ExtensionNotAllowed)
+        );
+        // A file that no longer loads leaves the previous policy in place.
+        std::fs::write(file.path(), "[validation]\nmax_size = \"big\"\n").unwrap();
+        source.reload(&current);
+        assert_eq!(check(&policy(), "cat.png"), Ok(()));
+        assert_eq!(policy().max_size, None);
+    }
+}
@@ -0,0 +1,264 @@
+use crate::{
+    AppState, SavedFile, Stored, UploadWriter, compressed_size, error::ApiError,
+    events::UploadTimes, link_duplicate, log_saved, naming::NameMeta, phash_stored, put_objects,
+    run_upload_cmd, sanitize_filename, signed::UploadGrant, storage_error, thumbnail_stored,
+    validation::FieldMeta, write_error,
+};
+use axum::{
+    extract::{
+        State,
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade, close_code},
+    },
+    http::{HeaderMap, Uri},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::{
+    fmt,
+    sync::{Arc, atomic::Ordering},
+};
+
+/// First message of a WebSocket upload, sent as text.
+#[derive(Deserialize)]
+struct WsHeader {
+    filename: String,
+    size: Option<u64>,
+    content_type: Option<String>,
+}
+
+/// Receives a file over a WebSocket: a JSON header, then binary chunks.
+///
+/// With a declared `size` the server finalizes once that many bytes arrived,
+/// replies with the saved file as JSON and closes. Without one the file is
+/// finalized when the client closes the socket.
+///
+/// A refused upload is closed with the error's code as the reason: 1008 when
+/// the file was turned away, 1002 when the messages were not header then
+/// chunks, and 1011 when the server failed to store it.
+pub async fn ws_upload(
+    State(state): State<Arc<AppState>>,
+    uri: Uri,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let times = UploadTimes::now(&headers, state.max_client_time_skew);
+    if state.draining.load(Ordering::Relaxed) {
+        return ApiError::ShuttingDown.into_response();
+    }
+    let grant = match UploadGrant::authorize(&state, &uri) {
+        Ok(grant) => grant,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = state.check_inodes().await {
+        return e.into_response();
+    }
+    ws.on_upgrade(move |socket| handle_ws_upload(socket, state, grant, times))
+}
+
+async fn handle_ws_upload(
+    mut socket: WebSocket,
+    state: Arc<AppState>,
+    grant: Option<UploadGrant>,
+    times: UploadTimes,
+) {
+    let mut closed = false;
+    let frame = match receive_file(&mut socket, &state, grant.as_ref(), &mut closed).await {
+        Ok(saved) => {
+            log_saved(&saved, &times);
+            let reply = serde_json::to_string(&saved).unwrap_or_default();
+            if let Some(events) = &state.events {
+                events.upload(&saved, &serde_json::Map::new(), &times);
+            }
+            if let Some(cmd) = &state.on_upload_cmd {
+                tokio::spawn(run_upload_cmd(
+                    cmd.clone(),
+                    saved,
+                    "{}".to_string(),
+                    state.errors.clone(),
+                ));
+            }
+            if !closed {
+                let _ = socket.send(Message::Text(reply.into())).await;
+            }
+            CloseFrame {
+                code: close_code::NORMAL,
+                reason: "saved".into(),
+            }
+        }
+        Err(err) => {
+            let code = if err == ApiError::UnexpectedMessage {
+                close_code::PROTOCOL
+            } else if err.status().is_server_error() {
+                close_code::ERROR
+            } else {
+                close_code::POLICY
+            };
+            CloseFrame {
+                code,
+                reason: err.code().into(),
+            }
+        }
+    };
+    if closed {
+        // Drives out the close reply queued when the client's close arrived.
+        let _ = socket.recv().await;
+    } else {
+        let _ = socket.send(Message::Close(Some(frame))).await;
+    }
+}
+
+async fn receive_file(
+    socket: &mut WebSocket,
+    state: &AppState,
+    grant: Option<&UploadGrant>,
+    closed: &mut bool,
+) -> Result<SavedFile, ApiError> {
+    let header: WsHeader = match socket.recv().await {
+        Some(Ok(Message::Text(text))) => serde_json::from_str(&text)
+            .map_err(|e| refuse(state, ApiError::UnexpectedMessage, e))?,
+        _ => {
+            return Err(refuse(
+                state,
+                ApiError::UnexpectedMessage,
+                "expected a JSON header as the first message",
+            ));
+        }
+    };
+    let requested = grant.map_or(&header.filename, |grant| &grant.name);
+    let filename = sanitize_filename(requested, state.reject_dotfiles)
+        .map_err(|e| refuse(state, e, format!("invalid filename {:?}", requested)))?;
+    state
+        .policy()
+        .validate(&FieldMeta {
+            filename: &filename,
+            declared_length: header.size,
+        })
+        .map_err(|reason| refuse(state, reason.into(), reason))?;
+    let name = NameMeta::new(&filename);
+    // Sockets bypass the request body limit, so it caps their files instead.
+    let max_size = grant.map_or(state.max_request_size as u64, |grant| {
+        grant.max_size.min(state.max_request_size as u64)
+    });
+    if header.size.is_some_and(|size| size > max_size) {
+        return Err(refuse(
+            state,
+            ApiError::FileTooLarge,
+            format!("declared size exceeds the limit of {} bytes", max_size),
+        ));
+    }
+    let stored = write_chunks(socket, &name, header.size, max_size, state, closed).await?;
+    let filepath = stored.path;
+    let deduplicated = link_duplicate(state, &stored.sha256, &filepath).await;
+    let compressed_size = compressed_size(&filepath, state).await;
+    let phash = phash_stored(&filepath, state).await;
+    let thumbnail = thumbnail_stored(&filepath, state).await;
+    let content_type = header
+        .content_type
+        .unwrap_or_else(|| crate::DEFAULT_CONTENT_TYPE.to_string());
+    let mut saved = SavedFile {
+        deduplicated,
+        compressed_size,
+        phash,
+        thumbnail,
+        object: None,
+        path: filepath,
+        filename,
+        content_type,
+        size: stored.size,
+        declared_length: header.size,
+        sha256: stored.sha256,
+        metadata_stripped: stored.metadata_stripped,
+        file_type: stored.file_type,
+    };
+    put_objects(state, std::slice::from_mut(&mut saved))
+        .await
+        .map_err(|e| storage_error(e, state))?;
+    Ok(saved)
+}
+
+/// Logs why an upload was refused, returning the error it is closed with.
+fn refuse(state: &AppState, err: ApiError, detail: impl fmt::Display) -> ApiError {
+    state
+        .errors
+        .warn(format!("rejected WebSocket upload: {}", detail));
+    err
+}
+
+async fn write_chunks(
+    socket: &mut WebSocket,
+    name: &NameMeta,
+    expected: Option<u64>,
+    max_size: u64,
+    state: &AppState,
+    closed: &mut bool,
+) -> Result<Stored, ApiError> {
+    let filepath = state.stored_path(name);
+    let mut writer = UploadWriter::create(&filepath, &name.filename, state)
+        .await
+        .map_err(|e| storage_error(e, state))?;
+    writer.limit_size(max_size);
+    match receive_chunks(socket, &mut writer, expected, state, closed).await {
+        Ok(()) => writer.finish(name).await.map_err(|e| write_error(e, state)),
+        Err(err) => {
+            writer.discard(&filepath).await;
+            Err(err)
+        }
+    }
+}
+
+async fn receive_chunks(
+    socket: &mut WebSocket,
+    writer: &mut UploadWriter<'_>,
+    expected: Option<u64>,
+    state: &AppState,
+    closed: &mut bool,
+) -> Result<(), ApiError> {
+    let mut size = 0;
+    while expected.is_none_or(|expected| (size as u64) < expected) {
+        match socket.recv().await {
+            Some(Ok(Message::Binary(chunk))) => {
+                writer
+                    .write(&chunk)
+                    .await
+                    .map_err(|e| write_error(e, state))?;
+                size += chunk.len();
+            }
+            Some(Ok(Message::Close(_))) => {
+                *closed = true;
+                break;
+            }
+            Some(Ok(Message::Text(_))) => {
+                return Err(refuse(
+                    state,
+                    ApiError::UnexpectedMessage,
+                    "unexpected text message",
+                ));
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => {
+                return Err(refuse(
+                    state,
+                    ApiError::UnexpectedMessage,
+                    format!("WebSocket error: {}", e),
+                ));
+            }
+            None => {
+                return Err(refuse(
+                    state,
+                    ApiError::UnexpectedMessage,
+                    "connection lost before the upload finished",
+                ));
+            }
+        }
+    }
+    if let Some(expected) = expected
+        && expected != size as u64
+    {
+        return Err(refuse(
+            state,
+            ApiError::LengthMismatch,
+            format!("received {} bytes, header declared {}", size, expected),
+        ));
+    }
+    Ok(())
+}
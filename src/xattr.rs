@@ -0,0 +1,109 @@
+use crate::{AppState, filetype::FileType};
+use anyhow::{Result, anyhow};
+use std::{
+    ffi::CString,
+    io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::atomic::Ordering,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::task;
+
+/// Attribute holding the filename an upload was stored from, with `--xattr-metadata`.
+const FILENAME_ATTR: &str = "user.petguard.filename";
+/// Attribute holding the Unix time an upload was stored, with `--xattr-metadata`.
+const UPLOADED_ATTR: &str = "user.petguard.uploaded";
+/// Attribute holding libmagic's description of an upload, with `--xattr-metadata`
+/// and `--use-libmagic`.
+const TYPE_ATTR: &str = "user.petguard.type";
+
+/// Parses an `--xattr` spec of the form `namespace.key=value`.
+pub fn parse_xattr(spec: &str) -> Result<(String, Vec<u8>)> {
+    match spec.split_once('=') {
+        Some((name, value)) if name.contains('.') && !name.contains('\0') => {
+            Ok((name.to_string(), value.as_bytes().to_vec()))
+        }
+        _ => Err(anyhow!(
+            "invalid xattr `{}`, expected namespace.key=value such as user.origin=upload",
+            spec
+        )),
+    }
+}
+
+/// Sets the `--xattr` attributes, and with `--xattr-metadata` the filename,
+/// upload time and any detected type, on the file at `path`.
+///
+/// Filesystems without extended attributes are warned about once and
+/// otherwise ignored; any other failure fails the upload.
+pub async fn apply(
+    path: &Path,
+    filename: &str,
+    file_type: Option<&FileType>,
+    state: &AppState,
+) -> Result<()> {
+    if state.xattrs.is_empty() && !state.xattr_metadata {
+        return Ok(());
+    }
+    let mut attrs = state.xattrs.clone();
+    if state.xattr_metadata {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        attrs.push((FILENAME_ATTR.to_string(), filename.as_bytes().to_vec()));
+        attrs.push((UPLOADED_ATTR.to_string(), now.to_string().into_bytes()));
+        if let Some(file_type) = file_type {
+            attrs.push((
+                TYPE_ATTR.to_string(),
+                file_type.description.as_bytes().to_vec(),
+            ));
+        }
+    }
+    let target = path.to_path_buf();
+    let result = task::spawn_blocking(move || {
+        attrs
+            .iter()
+            .try_for_each(|(name, value)| set(&target, name, value).map_err(|e| (name.clone(), e)))
+    })
+    .await?;
+    match result {
+        Ok(()) => Ok(()),
+        Err((_, e)) if e.raw_os_error() == Some(libc::ENOTSUP) => {
+            if !state.xattr_unsupported.swap(true, Ordering::Relaxed) {
+                tracing::warn!(
+                    "extended attributes are not supported for {:?}, storing uploads without them",
+                    path
+                );
+            }
+            Ok(())
+        }
+        Err((name, e)) => Err(anyhow!("Failed to set {} on {:?}: {}", name, path, e)),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+    // SAFETY: both strings are NUL-terminated and `value` is valid for its length.
+    let rc = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+}